@@ -11,6 +11,8 @@ fn main() {
             "x86_64"
         } else if cfg!(target_arch = "i686") {
             "i686"
+        } else if cfg!(target_arch = "aarch64") {
+            "aarch64"
         } else if cfg!(target_arch = "arm") {
             "arm"
         } else if cfg!(target_arch = "mips") {
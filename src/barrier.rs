@@ -0,0 +1,100 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use std::mem;
+use std::sync::Mutex;
+
+use pulse::{Signal, Pulse};
+
+/// A barrier enables multiple Fibers to synchronize the beginning of some computation.
+///
+/// Fibers waiting on the barrier are suspended on a `Signal`, so their driver is free
+/// to run other Fibers until the last one arrives. Once all of them have arrived they
+/// are released together and the barrier resets itself for the next phase.
+///
+/// ```ignore
+/// let barrier = Arc::new(Barrier::new(2));
+/// let b = barrier.clone();
+/// let fiber = Fiber::spawn(move|| {
+///     b.wait();
+///     println!("Both arrived");
+/// });
+/// ```
+pub struct Barrier {
+    state: Mutex<BarrierState>,
+    num_fibers: usize,
+}
+
+struct BarrierState {
+    count: usize,
+    phase: usize,
+    waiters: Vec<Pulse>,
+}
+
+impl Barrier {
+    /// Create a new barrier that can block a given number of Fibers.
+    pub fn new(n: usize) -> Barrier {
+        Barrier {
+            state: Mutex::new(BarrierState {
+                count: 0,
+                phase: 0,
+                waiters: Vec::new(),
+            }),
+            num_fibers: n,
+        }
+    }
+
+    /// Block the current Fiber until all Fibers have rendezvoused here.
+    ///
+    /// A single Fiber, the last one to arrive, is the leader of each phase and gets
+    /// `true` back; all the others get `false`.
+    pub fn wait(&self) -> bool {
+        let signal = {
+            let mut state = self.state.lock().unwrap();
+            state.count += 1;
+
+            if state.count < self.num_fibers {
+                let (signal, pulse) = Signal::new();
+                state.waiters.push(pulse);
+                signal
+            } else {
+                state.count = 0;
+                state.phase += 1;
+                let waiters = mem::replace(&mut state.waiters, Vec::new());
+                drop(state);
+
+                // Wake up everybody outside of the lock
+                for pulse in waiters.into_iter() {
+                    pulse.pulse();
+                }
+                return true;
+            }
+        };
+
+        signal.wait().unwrap();
+        false
+    }
+
+    /// Number of phases completed so far
+    pub fn phase(&self) -> usize {
+        self.state.lock().unwrap().phase
+    }
+}
@@ -0,0 +1,138 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use test::Bencher;
+
+use pulse::Signal;
+
+use fiber::{self, Fiber};
+use builder::Builder;
+
+const JOBS: usize = 10000;
+
+#[bench]
+fn bench_spawn_run(b: &mut Bencher) {
+    b.iter(|| {
+        for _ in 0..JOBS {
+            Fiber::spawn(move|| {}).run();
+        }
+    });
+}
+
+// The same without recycling stacks: one mmap, mprotect and munmap per Fiber
+#[bench]
+fn bench_spawn_run_unpooled(b: &mut Bencher) {
+    b.iter(|| {
+        for _ in 0..JOBS {
+            Builder::new().pool_stack(false).spawn(move|| {}).run();
+        }
+    });
+}
+
+// The same with the munmaps batched by a thousand
+#[bench]
+fn bench_spawn_run_unpooled_deferred(b: &mut Bencher) {
+    fiber::set_unmap_batch(1000);
+    b.iter(|| {
+        for _ in 0..JOBS {
+            Builder::new().pool_stack(false).spawn(move|| {}).run();
+        }
+        fiber::flush_deferred_unmaps();
+    });
+    fiber::set_unmap_batch(0);
+}
+
+#[bench]
+fn bench_reset_run(b: &mut Bencher) {
+    let mut fiber = Fiber::spawn(move|| {});
+    fiber.run();
+
+    b.iter(|| {
+        for _ in 0..JOBS {
+            fiber.reset(move|| {});
+            fiber.run();
+        }
+    });
+}
+
+fn noop() {}
+
+// One iteration is one round trip: switch into the Fiber and back out
+#[bench]
+fn bench_noop_round_trip(b: &mut Bencher) {
+    let mut fiber = fiber::spawn_noop();
+    fiber.run();
+
+    b.iter(|| {
+        fiber.reset(noop);
+        fiber.run();
+    });
+}
+
+// The "do some work, yield once, finish" shape, driven the way test_fiber_yield
+// drives it
+#[bench]
+fn bench_single_yield(b: &mut Bencher) {
+    b.iter(|| {
+        for _ in 0..JOBS {
+            let (s, p) = Signal::new();
+            let fiber = Fiber::spawn(move|| {
+                s.wait().unwrap();
+            });
+            fiber.run();
+            p.pulse();
+            fiber.run();
+        }
+    });
+}
+
+// Many fibers hammering the top of their stacks in turn, the case where the
+// stacks alias in the cache
+fn run_cache_workload(colors: usize, b: &mut Bencher) {
+    let fibers: Vec<_> = (0..256).map(|_| {
+        Builder::new().stack_colors(colors).spawn(move|| {
+            // Never finishes, each run is one round
+            let mut hot = [0usize; 8];
+            loop {
+                for slot in hot.iter_mut() {
+                    *slot = ::test::black_box(slot.wrapping_add(1));
+                }
+                Fiber::sched();
+            }
+        })
+    }).collect();
+
+    b.iter(|| {
+        for fiber in fibers.iter() {
+            fiber.run();
+        }
+    });
+}
+
+#[bench]
+fn bench_stack_uncolored(b: &mut Bencher) {
+    run_cache_workload(0, b);
+}
+
+#[bench]
+fn bench_stack_colored(b: &mut Bencher) {
+    run_cache_workload(64, b);
+}
@@ -19,7 +19,14 @@
 // IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
 // CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-use fiber::{Fiber, Handle, Options};
+use std::env::page_size;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+
+use pulse;
+
+use stack::StackArena;
+use fiber::{Fiber, FinishReason, Handle, Options, SpawnError, TraceContext};
 
 /// Fiber configuration. Provides detailed control over the properties and behavior of new Fibers.
 ///
@@ -32,6 +39,12 @@ use fiber::{Fiber, Handle, Options};
 /// ```
 pub struct Builder {
     opts: Options,
+    name_prefix: Option<String>,
+}
+
+lazy_static! {
+    // Next suffix of each prefix of `Builder::name_prefix`
+    static ref NAME_COUNTERS: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
 }
 
 impl Builder {
@@ -39,18 +52,133 @@ impl Builder {
     pub fn new() -> Builder {
         Builder {
             opts: Default::default(),
+            name_prefix: None,
         }
     }
 
     /// Name the Fiber-to-be. Currently the name is used for identification only in panic messages.
     pub fn name(mut self, name: String) -> Builder {
         self.opts.name = Some(name);
+        self.name_prefix = None;
+        self
+    }
+
+    /// Name the Fiber-to-be `prefix` followed by a dash and a counter of the Fibers
+    /// spawned with that prefix, starting at 0: "worker-0", "worker-1" and so on.
+    /// The number is taken when the Fiber is spawned.
+    pub fn name_prefix(mut self, prefix: &str) -> Builder {
+        self.name_prefix = Some(prefix.to_string());
         self
     }
 
-    /// Set the size of the stack for the new Fiber.
+    /// Set the size of the stack for the new Fiber, rounded up to whole pages. 0 asks
+    /// for the smallest stack a Fiber can get instead of the default 1MiB.
     pub fn stack_size(mut self, size: usize) -> Builder {
-        self.opts.stack_size = size;
+        let page = page_size();
+        // Raised to the minimum at spawn
+        self.opts.stack_size = (size + page - 1) / page * page;
+        self
+    }
+
+    /// Choose whether the stack of the new Fiber is pooled. Unpooled stacks are unmapped
+    /// as soon as the Fiber is dropped, instead of being kept around for reuse.
+    pub fn pool_stack(mut self, pool: bool) -> Builder {
+        self.opts.pool_stack = pool;
+        self
+    }
+
+    /// Choose whether the stack of the new Fiber grows with the size of the closure it
+    /// runs. This is a heuristic, a big enough stack is not guaranteed.
+    pub fn size_heuristic(mut self, enable: bool) -> Builder {
+        self.opts.size_heuristic = enable;
+        self
+    }
+
+    /// Spread the first frames of new Fibers across `colors` cache-line offsets, to
+    /// reduce cache conflicts between many Fibers. 0 or 1 disables coloring.
+    pub fn stack_colors(mut self, colors: usize) -> Builder {
+        self.opts.stack_colors = colors;
+        self
+    }
+
+    /// Choose whether the panic message of the new Fiber is reported, see
+    /// `fiber::set_panic_sink`. The payload can be recovered either way.
+    pub fn print_panic(mut self, print: bool) -> Builder {
+        self.opts.print_panic = print;
+        self
+    }
+
+    /// Abort the process if the new Fiber panics, instead of leaving it `Panicked`.
+    /// The panic message is reported first, even if `print_panic` is disabled.
+    pub fn abort_on_panic(mut self, abort: bool) -> Builder {
+        self.opts.abort_on_panic = abort;
+        self
+    }
+
+    /// Have `sched` handle the signal waits of the new Fiber, instead of
+    /// `fiber::Resume`, whenever it is not run with `Handle::run_with`.
+    pub fn scheduler<S>(mut self, sched: S) -> Builder
+        where S: pulse::Scheduler + Send + Sync + 'static
+    {
+        self.opts.scheduler = Some(Arc::new(sched));
+        self
+    }
+
+    /// Fill the stack of the new Fiber with a pattern, so that its peak usage can be
+    /// read with `Handle::stack_high_water`. This commits the whole stack.
+    pub fn stack_watermark(mut self, watermark: bool) -> Builder {
+        self.opts.stack_watermark = watermark;
+        self
+    }
+
+    /// Give the new Fiber tracing identifiers, instead of inheriting those of the
+    /// current Fiber. See `fiber::trace_context`.
+    pub fn trace_context(mut self, trace: TraceContext) -> Builder {
+        self.opts.trace_context = Some(trace);
+        self
+    }
+
+    /// Warn, once, when the new Fiber calls `Fiber::sched` more than `rate` times a
+    /// second. With `coalesce`, the yields beyond that return right away instead of
+    /// switching out of the Fiber.
+    pub fn max_switch_rate(mut self, rate: u32, coalesce: bool) -> Builder {
+        self.opts.max_switch_rate = Some(rate);
+        self.opts.coalesce_yields = coalesce;
+        self
+    }
+
+    /// Protect `pages` pages below the stack of the new Fiber instead of one, to
+    /// catch frames that jump over a single guard page. They come on top of the
+    /// stack size.
+    pub fn guard_pages(mut self, pages: usize) -> Builder {
+        self.opts.guard_pages = pages;
+        self
+    }
+
+    /// Place the stack of the new Fiber in the next slot of `arena`, instead of taking
+    /// it from the pool, so that it lands at the same address on every run.
+    pub fn stack_arena(mut self, arena: Arc<StackArena>) -> Builder {
+        self.opts.stack_arena = Some(arena);
+        self
+    }
+
+    /// Cancel the new Fiber when its last handle is dropped while it is suspended, so
+    /// that the destructors of its frames run. See `Handle::cancel`.
+    pub fn cancel_on_drop(mut self, cancel: bool) -> Builder {
+        self.opts.cancel_on_drop = cancel;
+        self
+    }
+
+    /// Register a callback that is called once the Fiber ends, on the Fiber itself.
+    pub fn on_finish<F>(mut self, f: F) -> Builder
+        where F: FnOnce(FinishReason) + Send + 'static
+    {
+        let mut f = Some(f);
+        self.opts.on_finish = Some(Box::new(move|reason| {
+            if let Some(f) = f.take() {
+                f(reason)
+            }
+        }));
         self
     }
 
@@ -58,6 +186,24 @@ impl Builder {
     pub fn spawn<F>(self, f: F) -> Handle
         where F: FnOnce() + Send + 'static
     {
-        Fiber::spawn_opts(f, self.opts)
+        Fiber::spawn_opts(f, self.into_opts())
+    }
+
+    /// Spawn a new Fiber, and return a handle for it, or why it could not be spawned.
+    pub fn try_spawn<F>(self, f: F) -> Result<Handle, SpawnError>
+        where F: FnOnce() + Send + 'static
+    {
+        Fiber::try_spawn_opts(f, self.into_opts())
+    }
+
+    fn into_opts(self) -> Options {
+        let mut opts = self.opts;
+        if let Some(prefix) = self.name_prefix {
+            let mut counters = NAME_COUNTERS.lock().unwrap();
+            let counter = counters.entry(prefix.clone()).or_insert(0);
+            opts.name = Some(format!("{}-{}", prefix, *counter));
+            *counter += 1;
+        }
+        opts
     }
 }
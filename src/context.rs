@@ -54,13 +54,40 @@ impl Context {
     ///        task is spawned as much as possible
     pub fn new<F>(init: InitFn, arg: usize, start: F, stack: &mut Stack) -> Context
             where F: FnOnce() + Send + 'static {
-        let sp: *const usize = stack.end();
+        Context::with_offset(init, arg, start, stack, 0)
+    }
+
+    /// Create a new context like `new`, with its initial stack pointer `offset` bytes
+    /// below the end of `stack`
+    pub fn with_offset<F>(init: InitFn, arg: usize, start: F, stack: &mut Stack, offset: usize) -> Context
+            where F: FnOnce() + Send + 'static {
+        let mut ctx = Context::empty();
+        ctx.reset_with_offset(init, arg, start, stack, offset);
+        ctx
+    }
+
+    /// Re-initialize this context in place, so that it runs `start` on `stack` the
+    /// next time it is swapped in
+    ///
+    /// This is the same as `Context::new`, except that the register block is reused
+    /// instead of being allocated again.
+    pub fn reset<F>(&mut self, init: InitFn, arg: usize, start: F, stack: &mut Stack)
+            where F: FnOnce() + Send + 'static {
+        self.reset_with_offset(init, arg, start, stack, 0)
+    }
+
+    /// Re-initialize this context in place like `reset`, with its initial stack
+    /// pointer `offset` bytes below the end of `stack`
+    pub fn reset_with_offset<F>(&mut self, init: InitFn, arg: usize, start: F, stack: &mut Stack,
+                                offset: usize)
+            where F: FnOnce() + Send + 'static {
+        let sp: *const usize = (stack.end() as usize - offset) as *const usize;
         let sp: *mut usize = sp as *mut usize;
         // Save and then immediately load the current context,
         // which we will then modify to call the given function when restored
-        let mut regs = Box::new(Registers::new());
+        *self.regs = Registers::new();
 
-        initialize_call_frame(&mut regs, init, arg, unsafe { transmute(Box::new(Thunk::new(start))) }, sp);
+        initialize_call_frame(&mut self.regs, init, arg, unsafe { transmute(Box::new(Thunk::new(start))) }, sp);
 
         // Scheduler tasks don't have a stack in the "we allocated it" sense,
         // but rather they run on pthreads stacks. We have complete control over
@@ -68,16 +95,18 @@ impl Context {
         // overflow). Additionally, their coroutine stacks are listed as being
         // zero-length, so that's how we detect what's what here.
         let stack_base: *const usize = stack.start();
-        let bounds =
+        self.stack_bounds =
             if sp as libc::uintptr_t == stack_base as libc::uintptr_t {
                 None
             } else {
                 Some((stack_base as usize, sp as usize))
             };
-        return Context {
-            regs: regs,
-            stack_bounds: bounds,
-        }
+    }
+
+    /// The stack pointer saved in this context, as of the last time it was swapped
+    /// out, or as set up by `new`
+    pub fn stack_pointer(&self) -> usize {
+        self.regs.stack_pointer()
     }
 
     /* Switch contexts
@@ -85,6 +114,18 @@ impl Context {
     Suspend the current execution context and resume another by
     saving the registers values of the executing thread to a Context
     then loading the registers from a previously saved Context.
+
+    A signal may be delivered at any point of the swap. Its handler runs on
+    whichever stack the stack pointer is on at that instant, below the data
+    of that stack, so it never sees or clobbers a half-written Context: the
+    registers it interrupted are restored on return like any others, and the
+    swap carries on. Two things are not covered, and are up to the handler:
+
+    * between `record_rust_managed_stack_bounds` and `rust_swap_registers` the
+      stack limit already belongs to the incoming stack, so a handler must not
+      rely on the stack limit check (mark it `#[no_stack_check]`);
+    * a handler must not run, resume or yield Fibers, since the Context being
+      swapped may only be half saved.
     */
     pub fn swap(out_context: &mut Context, in_context: &Context) {
         debug!("swapping contexts");
@@ -157,6 +198,10 @@ struct Registers {
 
 #[cfg(target_arch = "x86")]
 impl Registers {
+    fn stack_pointer(&self) -> usize {
+        self.esp as usize
+    }
+
     fn new() -> Registers {
         Registers {
             eax: 0, ebx: 0, ecx: 0, edx: 0,
@@ -206,6 +251,10 @@ struct Registers {
 
 #[cfg(all(windows, target_arch = "x86_64"))]
 impl Registers {
+    fn stack_pointer(&self) -> usize {
+        self.gpr[1] as usize // RUSTRT_RSP
+    }
+
     fn new() -> Registers {
         Registers {
             gpr: [0; 14],
@@ -224,6 +273,10 @@ struct Registers {
 
 #[cfg(all(not(windows), target_arch = "x86_64"))]
 impl Registers {
+    fn stack_pointer(&self) -> usize {
+        self.gpr[1] as usize // RUSTRT_RSP
+    }
+
     fn new() -> Registers {
         Registers {
             gpr: [0; 10],
@@ -274,15 +327,20 @@ fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkpt
     regs.gpr[RUSTRT_RBP] = 0;
 }
 
+// r0--r14 and cpsr, then d8--d15 from #72, see arm/_context.S
 #[cfg(target_arch = "arm")]
 #[repr(C)]
 #[derive(Debug)]
-struct Registers([libc::uintptr_t; 32]);
+struct Registers([libc::uintptr_t; 40]);
 
 #[cfg(target_arch = "arm")]
 impl Registers {
+    fn stack_pointer(&self) -> usize {
+        self.0[13] as usize // sp, r13
+    }
+
     fn new() -> Registers {
-        Registers([0; 32])
+        Registers([0; 40])
     }
 }
 
@@ -309,6 +367,49 @@ fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkpt
     regs[14] = rust_bootstrap_green_task as libc::uintptr_t;   // #56 pc, r14 --> lr
 }
 
+// x19--x30, sp, d8--d15 and a padding slot, see aarch64/_context.S
+#[cfg(target_arch = "aarch64")]
+#[repr(C)]
+#[derive(Debug)]
+struct Registers([libc::uintptr_t; 22]);
+
+#[cfg(target_arch = "aarch64")]
+impl Registers {
+    fn stack_pointer(&self) -> usize {
+        self.0[12] as usize // sp
+    }
+
+    fn new() -> Registers {
+        Registers([0; 22])
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, arg: usize, thunkptr: *mut (), sp: *mut usize) {
+    extern { fn rust_bootstrap_green_task(); } // same as the x64 arch
+
+    // sp of aapcs64 is 16-byte aligned, also when it is used
+    let sp = align_down(sp);
+    let sp = mut_offset(sp, -2);
+
+    // The final frame record. 0 indicates the bottom of the stack
+    unsafe {
+        *sp = 0;
+        *mut_offset(sp, 1) = 0;
+    }
+
+    let &mut Registers(ref mut regs) = regs;
+
+    // Neither x0 nor x1 are saved on a context switch, so the shim moves x19/x20
+    // into them and then branches to the function in x21
+    regs[0] = arg as libc::uintptr_t;                          // x19
+    regs[1] = thunkptr as libc::uintptr_t;                     // x20
+    regs[2] = fptr as libc::uintptr_t;                         // x21
+    regs[10] = 0;                                              // x29, fp
+    regs[11] = rust_bootstrap_green_task as libc::uintptr_t;   // x30, lr
+    regs[12] = sp as libc::uintptr_t;                          // sp
+}
+
 #[cfg(any(target_arch = "mips",
           target_arch = "mipsel"))]
 #[repr(C)]
@@ -318,6 +419,10 @@ struct Registers([libc::uintptr_t; 32]);
 #[cfg(any(target_arch = "mips",
           target_arch = "mipsel"))]
 impl Registers {
+    fn stack_pointer(&self) -> usize {
+        self.0[29] as usize // sp
+    }
+
     fn new() -> Registers {
         Registers([0; 32])
     }
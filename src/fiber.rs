@@ -47,23 +47,49 @@
  *  Now the scheduler is awake again and it simply decides whether to put the coroutine to queue again or not,
  *  according to the coroutine's return status.
  *  And last, the scheduler continues the scheduling loop and selects a proper coroutine to wake up.
+ *
+ *  A suspended coroutine may be resumed by another thread than the one it last ran on. The
+ *  `running` flag of the Fiber orders the two: it is taken with Acquire by `Handle::resume`
+ *  and given back with Release once the coroutine has switched out, so everything the
+ *  coroutine and its previous driver wrote (its saved registers, its state, its whole stack)
+ *  is visible to the next driver. Moving a `Handle` through a channel orders them just as
+ *  well. Outside of `resume`, nothing synchronizes with a running coroutine: reading a Fiber
+ *  from another thread while it runs is a data race, which `Deref for Handle` checks in
+ *  debug builds. Thread-locals seen by a coroutine are those of whichever thread runs it.
  */
 
 use std::default::Default;
-use std::rt::util::min_stack;
 use thunk::Thunk;
-use std::mem::transmute;
+use std::mem::{self, transmute};
+use std::cmp;
+use std::thread;
+use std::env::page_size;
 use std::rt::unwind::try;
-use std::any::Any;
-use std::cell::UnsafeCell;
+use std::any::{Any, TypeId};
+use std::cell::{Cell, UnsafeCell};
 use std::ops::Deref;
 use std::ptr::{self, Unique};
 use std::fmt::{self, Debug};
+use std::error::Error;
+use std::collections::HashMap;
+#[cfg(feature = "yield-history")]
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 
 use pulse::{self, Signal};
+use mmap::MapError;
+use log::LogLevel;
+use time;
+use libc;
 
 use context::Context;
-use stack::{Stack};
+use sys;
+use stack::{self, Stack, StackArena, StackPool};
+
+pub use barrier::Barrier;
+pub use timer::sleep_ms;
 
 /// State of a Fiber
 #[derive(Debug, Clone)]
@@ -71,7 +97,11 @@ pub enum State {
     /// Pending its child to return
     Pending(Signal),
 
-    /// Time
+    /// Pending on a signal for at most the given milliseconds left
+    ///
+    /// Nothing wakes the Fiber up when the time is over: its driver has to keep
+    /// resuming it, which is a no-op until either the signal is pulsed or the time
+    /// is over.
     PendingTimeout(Signal, u32),
 
     /// Finished
@@ -79,6 +109,9 @@ pub enum State {
 
     /// Panic happened inside, cannot be resumed again
     Panicked,
+
+    /// Currently running on a driver
+    Running,
 }
 
 impl State {
@@ -103,6 +136,13 @@ impl State {
             _ => false
         }
     }
+
+    pub fn is_running(&self) -> bool {
+        match self {
+            &State::Running => true,
+            _ => false
+        }
+    }
 }
 
 /// Return type of resuming.
@@ -110,21 +150,190 @@ impl State {
 /// See `Fiber::resume` for more detail
 pub type ResumeResult<T> = Result<T, Box<Any + Send>>;
 
-/// Fiber spawn options
+/// Reasons why `Handle::resume` could not resume a Fiber
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResumeError {
+    /// The Fiber is already being run, by another thread or further up the current one
+    Busy,
+}
+
+/// Reasons why a Fiber could not be spawned
 #[derive(Debug)]
+pub enum SpawnError {
+    /// Mapping a stack of the given size failed
+    StackAllocation(usize, MapError),
+
+    /// The guard page of a new stack could not be protected
+    GuardPage,
+
+    /// Spawning was attempted in a signal handler, see `enter_signal_context`
+    InSignalHandler,
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SpawnError::StackAllocation(size, ref e) =>
+                write!(f, "mmap for stack of size {} failed: {}", size, e),
+            SpawnError::GuardPage => write!(f, "Could not memory-protect guard page"),
+            SpawnError::InSignalHandler => write!(f, "Cannot spawn a Fiber in a signal handler"),
+        }
+    }
+}
+
+impl Error for SpawnError {
+    fn description(&self) -> &str {
+        match *self {
+            SpawnError::StackAllocation(..) => "stack allocation failed",
+            SpawnError::GuardPage => "guard page protection failed",
+            SpawnError::InSignalHandler => "spawn in a signal handler",
+        }
+    }
+}
+
+/// Where the life of a Fiber went, see `Handle::time_breakdown`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeBreakdown {
+    /// Nanoseconds spent running
+    pub running_ns: u64,
+
+    /// Nanoseconds spent pending, from yielding until being resumed again
+    pub pending_ns: u64,
+}
+
+/// Unique identifier of a Fiber
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FiberId(usize);
+
+static NEXT_FIBER_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Distributed tracing identifiers carried by a Fiber, see `Options::trace_context`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceContext {
+    /// Identifier of the whole trace
+    pub trace_id: u64,
+    /// Identifier of the span the Fiber works on
+    pub span_id: u64,
+}
+
+/// Why a Fiber stopped running, see `Options::on_finish`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The body of the Fiber returned
+    Finished,
+
+    /// The body of the Fiber panicked
+    Panicked,
+
+    /// The Fiber was unwound by `Handle::cancel`
+    Cancelled,
+}
+
+/// Fiber spawn options
 pub struct Options {
-    /// The size of the stack
+    /// The size of the stack, guard page included, at least `MIN_STACK_PAGES` pages
     pub stack_size: usize,
 
     /// The name of the Fiber
     pub name: Option<String>,
+
+    /// Whether the stack comes from, and goes back to, the thread's `StackPool`
+    pub pool_stack: bool,
+
+    /// Called once when the Fiber ends, whatever the reason
+    pub on_finish: Option<Box<FnMut(FinishReason) + Send>>,
+
+    /// Whether to grow the stack according to the size of the closure, see
+    /// `heuristic_stack_size`
+    pub size_heuristic: bool,
+
+    /// Number of stack colors to spread new Fibers across, 0 or 1 for none, see
+    /// `STACK_COLOR_STRIDE`
+    pub stack_colors: usize,
+
+    /// Whether the panic message of the Fiber is reported, see `set_panic_sink`. The
+    /// payload is kept either way, see `Handle::take_panic`.
+    pub print_panic: bool,
+
+    /// Whether a panic of the Fiber aborts the process, after reporting it, instead
+    /// of leaving the Fiber `Panicked`
+    pub abort_on_panic: bool,
+
+    /// Scheduler handling the signal waits of the Fiber, instead of `Resume`, when it
+    /// is not run with `Handle::run_with`
+    pub scheduler: Option<Arc<pulse::Scheduler + Send + Sync>>,
+
+    /// Whether the stack is filled with a pattern when the Fiber is spawned, to
+    /// measure its peak usage with `Handle::stack_high_water`
+    pub stack_watermark: bool,
+
+    /// Tracing identifiers of the Fiber, see `trace_context`. Without them, the
+    /// Fiber inherits those of the Fiber it is spawned from.
+    pub trace_context: Option<TraceContext>,
+
+    /// Most `Fiber::sched` yields a second before a warning is logged for the Fiber,
+    /// once. None for no limit.
+    pub max_switch_rate: Option<u32>,
+
+    /// Whether the `Fiber::sched` yields beyond `max_switch_rate` return right away
+    /// instead of switching out of the Fiber
+    pub coalesce_yields: bool,
+
+    /// Number of inaccessible pages at the low end of the stack, at least one, see
+    /// `Stack::try_with_guard`
+    pub guard_pages: usize,
+
+    /// Arena to place the stack in, for reproducible stack addresses, instead of
+    /// taking it from the pool
+    pub stack_arena: Option<Arc<StackArena>>,
+
+    /// Whether dropping the last handle of a suspended Fiber cancels it first, see
+    /// `Handle::cancel`
+    pub cancel_on_drop: bool,
+}
+
+impl Debug for Options {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Options")
+            .field("stack_size", &self.stack_size)
+            .field("name", &self.name)
+            .field("pool_stack", &self.pool_stack)
+            .field("on_finish", &self.on_finish.is_some())
+            .field("size_heuristic", &self.size_heuristic)
+            .field("stack_colors", &self.stack_colors)
+            .field("print_panic", &self.print_panic)
+            .field("abort_on_panic", &self.abort_on_panic)
+            .field("scheduler", &self.scheduler.is_some())
+            .field("stack_watermark", &self.stack_watermark)
+            .field("trace_context", &self.trace_context)
+            .field("max_switch_rate", &self.max_switch_rate)
+            .field("coalesce_yields", &self.coalesce_yields)
+            .field("guard_pages", &self.guard_pages)
+            .field("stack_arena", &self.stack_arena)
+            .field("cancel_on_drop", &self.cancel_on_drop)
+            .finish()
+    }
 }
 
 impl Default for Options {
     fn default() -> Options {
         Options {
-            stack_size: min_stack(),
+            stack_size: DEFAULT_STACK_SIZE,
             name: None,
+            pool_stack: true,
+            on_finish: None,
+            size_heuristic: false,
+            stack_colors: 0,
+            print_panic: true,
+            abort_on_panic: false,
+            scheduler: None,
+            stack_watermark: false,
+            trace_context: None,
+            max_switch_rate: None,
+            coalesce_yields: false,
+            guard_pages: 1,
+            stack_arena: None,
+            cancel_on_drop: false,
         }
     }
 }
@@ -145,7 +354,23 @@ unsafe impl Send for Handle {}
 impl Drop for Handle {
     fn drop(&mut self) {
         unsafe {
+            if self.get_inner().cancel_on_drop {
+                if let Err(ResumeError::Busy) = self.cancel() {
+                    warn!("Fiber {:?} ({:?}) could not be cancelled on drop, it is being run",
+                          self.get_inner().name(), self.get_inner().id);
+                }
+            }
+            if self.get_inner().running.load(Ordering::Acquire) {
+                // Still running somewhere else, on its own stack: freeing it here
+                // would pull both out from under it
+                warn!("Fiber {:?} ({:?}) was dropped while being run, leaking it",
+                      self.get_inner().name(), self.get_inner().id);
+                return;
+            }
             let p = Box::from_raw(*self.0);
+            if cfg!(debug_assertions) && p.resume_count == 0 && p.state.is_pending() {
+                warn!("Fiber {:?} ({:?}) was dropped without ever being run", p.name(), p.id);
+            }
             drop(p);
         }
     }
@@ -162,26 +387,277 @@ impl Handle {
         & *self.0.get()
     }
 
+    /// Run the Fiber until it yields, and return its new state
+    ///
+    /// Panics if the Fiber is already being run, see `resume` for a non-panicking
+    /// version.
     pub fn run(&self) -> State {
-        // Only run if the signal is set
-        match self.state {
-            State::Pending(ref sig) | State::PendingTimeout(ref sig, _) => {
-                if !sig.is_pending() {
-                    let mut ctx = Parent{
-                        context: Context::empty(),
-                        running: *self.0
-                    };
-                    PARENT_CONTEXT.with(|pctx| {
-                        unsafe { *pctx.get() = &mut ctx as *mut Parent; }
-                    });
-                    pulse::with_scheduler(|| { unsafe {
-                        Context::swap(&mut ctx.context, &(**self.0).saved_context);
-                    }}, Box::new(Resume));
+        match self.resume() {
+            Ok(state) => state,
+            Err(ResumeError::Busy) => panic!("Fiber {:?} is already running", self),
+        }
+    }
+
+    /// Run the Fiber until it yields, with `sched` handling its signal waits, and
+    /// return its new state
+    ///
+    /// The scheduler is only installed until the Fiber yields: a wait that suspends
+    /// the Fiber continues under whichever scheduler it is resumed with, so `sched`
+    /// must not use itself after switching out of the Fiber. Panics if the Fiber is
+    /// already being run, like `run`.
+    pub fn run_with<S: pulse::Scheduler + 'static>(&self, sched: S) -> State {
+        match self.resume_inner_with(None, Box::new(sched)) {
+            Ok((state, _)) => state,
+            Err(ResumeError::Busy) => panic!("Fiber {:?} is already running", self),
+        }
+    }
+
+    /// Run the Fiber until it yields, and return its new state
+    ///
+    /// A Fiber can only run on one thread at a time; if it is already running this
+    /// returns `ResumeError::Busy` instead of switching into it.
+    pub fn resume(&self) -> Result<State, ResumeError> {
+        self.resume_inner(None).map(|(state, _)| state)
+    }
+
+    /// Run the Fiber until it yields, and return the value it gave to
+    /// `Fiber::yield_value::<T>`
+    ///
+    /// Returns `Ok(None)` if the Fiber finished, or suspended itself without yielding
+    /// a value of type `T`; `state` tells them apart. Returns `Err` if the Fiber
+    /// panicked. Panics if the Fiber is already being run, like `run`.
+    pub fn resume_yield<T: Any>(&self) -> ResumeResult<Option<T>> {
+        let (state, mut output) = match self.resume_inner(None) {
+            Ok(res) => res,
+            Err(ResumeError::Busy) => panic!("Fiber {:?} is already running", self),
+        };
+        if state.is_panic() {
+            return Err(self.panic_payload());
+        }
+        Ok(unsafe { take_slot::<T>(&mut output) }.unwrap_or(None))
+    }
+
+    /// Run the Fiber until it yields, handing it `val`, and return its new state
+    ///
+    /// The Fiber gets `val` as the return value of the `Fiber::sched_value::<T>` it is
+    /// suspended in. The Fiber panics if it is suspended anywhere else, or in a
+    /// `sched_value` of another type; it is then left `Panicked`. If the Fiber is not
+    /// ready to run, or if it is finished, `val` is dropped here instead.
+    pub fn resume_with<T: Any>(&self, val: T) -> Result<State, ResumeError> {
+        let mut slot = Some(val);
+        self.resume_inner(Some((TypeId::of::<T>(), &mut slot as *mut Option<T> as *mut ())))
+            .map(|(state, _)| state)
+    }
+
+    /// Resume the Fiber, giving it `input`, and return its new state with the value
+    /// it yielded, if any
+    fn resume_inner(&self, input: Slot) -> Result<(State, Slot), ResumeError> {
+        let sched: Box<pulse::Scheduler> = match unsafe { self.get_inner() }.scheduler {
+            Some(ref sched) => Box::new(SharedScheduler(sched.clone())),
+            None => Box::new(Resume),
+        };
+        self.resume_inner_with(input, sched)
+    }
+
+    fn resume_inner_with(&self, input: Slot, sched: Box<pulse::Scheduler>)
+        -> Result<(State, Slot), ResumeError>
+    {
+        if sys::overflow::installed() {
+            sys::overflow::ensure_altstack();
+        }
+        let fiber = unsafe { self.get_inner() };
+        if fiber.running.compare_and_swap(false, true, Ordering::Acquire) {
+            return Err(ResumeError::Busy);
+        }
+
+        // Only run if the signal is set, or the timeout passed
+        let ready = match fiber.state {
+            State::Pending(ref sig) => !sig.is_pending(),
+            State::PendingTimeout(ref sig, _) => {
+                !sig.is_pending() ||
+                fiber.timeout_at.map_or(false, |at| time::precise_time_ns() >= at)
+            }
+            State::Finished | State::Panicked | State::Running => false,
+        } || (fiber.cancelled && !fiber.state.is_finished() && !fiber.state.is_panic());
+
+        let mut output = None;
+        if ready {
+            let mut ctx = Parent{
+                context: Context::empty(),
+                running: *self.0,
+                outer: ptr::null_mut(),
+                input: input,
+                output: None,
+                handle: self as *const Handle,
+            };
+            PARENT_CONTEXT.with(|pctx| unsafe {
+                ctx.outer = *pctx.get();
+                *pctx.get() = &mut ctx as *mut Parent;
+            });
+            unsafe {
+                // The signal has been consumed, forget about it so that nobody
+                // mistakes the Fiber as still waiting on it
+                (**self.0).resume_count += 1;
+                (**self.0).set_state(State::Running);
+            }
+            pulse::with_scheduler(|| { unsafe {
+                Context::swap(&mut ctx.context, &(**self.0).saved_context);
+            }}, sched);
+            // We may be a Fiber ourselves, which has to find its own parent
+            // again when it yields
+            PARENT_CONTEXT.with(|pctx| unsafe {
+                *pctx.get() = ctx.outer;
+            });
+            unsafe {
+                (**self.0).record_yield();
+            }
+            output = ctx.output;
+        }
+
+        let state = fiber.state.clone();
+        fiber.running.store(false, Ordering::Release);
+        Ok((state, output))
+    }
+
+    /// Unwind the Fiber from where it is suspended, running the destructors of its
+    /// frames, and leave it `Finished`
+    ///
+    /// The Fiber is switched into whatever it waits on, and panics there with a
+    /// payload of its own, which is neither reported nor kept; `on_finish` is told
+    /// `FinishReason::Cancelled`. A Fiber that never ran only drops its closure. Like
+    /// `join`, this drives the Fiber to completion on the calling thread: when a
+    /// destructor blocks on a signal while the Fiber unwinds, this waits on that
+    /// signal in turn before resuming it. Does nothing if the Fiber is already
+    /// finished or panicked, and returns `ResumeError::Busy` if it is being run
+    /// somewhere else.
+    pub fn cancel(&self) -> Result<(), ResumeError> {
+        loop {
+            {
+                let fiber = unsafe { &mut **self.0 };
+                if fiber.state.is_finished() || fiber.state.is_panic() {
+                    return Ok(());
                 }
+                fiber.cancelled = true;
+            }
+            match try!(self.resume_inner(None)).0 {
+                State::Pending(sig) => { let _ = sig.wait(); }
+                State::PendingTimeout(sig, ms) => { let _ = sig.wait_timeout_ms(ms); }
+                _ => {}
             }
-            State::Finished | State::Panicked => ()
         }
-        self.state.clone()
+    }
+
+    /// Take the payload of the panic of the Fiber
+    ///
+    /// Returns `None` if the Fiber has not panicked, or if the payload was already
+    /// taken, by this or by `join`.
+    pub fn take_panic(&self) -> Option<Box<Any + Send>> {
+        let fiber: &mut Fiber = unsafe { &mut **self.0 };
+        fiber.panic.take()
+    }
+
+    /// The payload of the panic of the Fiber, or a stand-in if it was already taken
+    fn panic_payload(&self) -> Box<Any + Send> {
+        self.take_panic().unwrap_or_else(|| Box::new("Fiber panicked") as Box<Any + Send>)
+    }
+
+    /// Run the Fiber until it is finished or panicked
+    ///
+    /// Whenever the Fiber is pending on a signal, this waits on that signal in turn:
+    /// from an OS thread this blocks the thread, from another Fiber this yields that
+    /// Fiber to its own driver. Returns right away if the Fiber is already finished.
+    /// If it is being run somewhere else, this keeps yielding until it's not.
+    pub fn join(&self) -> ResumeResult<()> {
+        loop {
+            match self.resume() {
+                Ok(State::Finished) => return Ok(()),
+                Ok(State::Panicked) => return Err(self.panic_payload()),
+                Ok(State::Pending(sig)) => { let _ = sig.wait(); }
+                Ok(State::PendingTimeout(sig, ms)) => { let _ = sig.wait_timeout_ms(ms); }
+                Ok(State::Running) => unreachable!(),
+                Err(ResumeError::Busy) => {
+                    if running_fiber().is_null() {
+                        thread::yield_now();
+                    } else {
+                        Fiber::sched();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Run the Fiber until it yields, and return its new state along with the last
+    /// progress it reported with `report_progress` since the previous call
+    ///
+    /// The progress is `None` if the Fiber reported nothing, or if it reported a value
+    /// of another type than `P`.
+    pub fn resume_with_progress<P: Any>(&self) -> Result<(State, Option<P>), ResumeError> {
+        let state = try!(self.resume());
+        let fiber: &mut Fiber = unsafe { &mut **self.0 };
+        let progress = fiber.progress.take().and_then(|p| p.downcast::<P>().ok());
+        Ok((state, progress.map(|p| *p)))
+    }
+
+    /// Run the Fiber until it yields, handing it `resp` as the response to the effect
+    /// it performed last, and return the next effect it performs, see `perform`
+    ///
+    /// Pass `None` to start the Fiber, or to resume it when it is not suspended in
+    /// `perform`. Returns `Ok(None)` if the Fiber finished, or suspended itself without
+    /// performing an effect of type `Req`, and `Err` if it panicked. Panics if the
+    /// Fiber is already being run, like `run`.
+    pub fn resume_effect<Req: Any, Resp: Any>(&self, resp: Option<Resp>) -> ResumeResult<Option<Req>> {
+        let mut slot = resp;
+        let input = if slot.is_some() {
+            Some((TypeId::of::<Resp>(), &mut slot as *mut Option<Resp> as *mut ()))
+        } else {
+            None
+        };
+        let (state, mut output) = match self.resume_inner(input) {
+            Ok(res) => res,
+            Err(ResumeError::Busy) => panic!("Fiber {:?} is already running", self),
+        };
+        if state.is_panic() {
+            return Err(self.panic_payload());
+        }
+        Ok(unsafe { take_slot::<Req>(&mut output) }.unwrap_or(None))
+    }
+
+    /// Turn this Handle into one that can be cloned and shared between threads
+    pub fn into_shared(self) -> SharedHandle {
+        SharedHandle(Arc::new(self))
+    }
+
+    /// Reuse a finished or panicked Fiber to run another closure
+    ///
+    /// The stack and the register block of the Fiber are kept, which makes resetting
+    /// a Fiber cheaper than spawning a new one. The closure itself is still boxed
+    /// on every reset, as each job captures its own state; closures without any
+    /// captures are zero-sized and do not allocate at all.
+    ///
+    /// A panicked Fiber has already been unwound up to `coroutine_initialize` before
+    /// it switched out for the last time, so nothing on its stack is still alive.
+    ///
+    /// Panics if the Fiber has neither finished nor panicked.
+    pub fn reset<F>(&mut self, f: F)
+        where F: FnOnce() + Send + 'static
+    {
+        let fiber: &mut Fiber = unsafe { &mut **self.0 };
+        assert!(fiber.state.is_finished() || fiber.state.is_panic(),
+                "Cannot reset a Fiber that has not finished");
+
+        fiber.panic = None;
+        fiber.cancelled = false;
+        fiber.unwinding = false;
+        // Nothing of the previous job carries over to the next one
+        fiber.locals.clear();
+        fiber.progress = None;
+        fiber.timeout_at = None;
+        fiber.switch_window = (0, 0);
+        fiber.switch_warned = false;
+        let stack = fiber.current_stack_segment.as_mut().unwrap();
+        fiber.saved_context.reset_with_offset(coroutine_initialize, 0, f, stack, fiber.stack_offset);
+        check_initial_sp(&fiber.saved_context, stack);
+        fiber.set_state(State::Pending(Signal::pulsed()));
     }
 
     /// Get the state of the Fiber
@@ -191,20 +667,203 @@ impl Handle {
             self.get_inner().state()
         }
     }
+
+    /// Get the number of bytes mapped for the stack of the Fiber
+    ///
+    /// Unlike `stack_size`, this counts the whole mapping, and may be larger than
+    /// what was requested when the stack came from the pool.
+    pub fn stack_mapped_bytes(&self) -> usize {
+        let fiber = unsafe { self.get_inner() };
+        fiber.current_stack_segment.as_ref().map(|s| s.mapped_len()).unwrap_or(0)
+    }
+
+    /// Get the peak stack usage of the Fiber so far, in bytes
+    ///
+    /// Only measured for Fibers spawned with `Options::stack_watermark`, 0 otherwise.
+    /// A Fiber that overflowed its stack is long gone, so this is at most the usable
+    /// size of the stack.
+    pub fn stack_high_water(&self) -> usize {
+        let fiber = unsafe { self.get_inner() };
+        if !fiber.stack_watermark {
+            return 0;
+        }
+        fiber.current_stack_segment.as_ref().map(|s| s.high_water()).unwrap_or(0)
+    }
+
+    /// Get how long the Fiber has been running and pending so far
+    ///
+    /// Only the completed periods are counted: the current one is added once the
+    /// Fiber changes state. A new Fiber is pending until it is first run.
+    pub fn time_breakdown(&self) -> TimeBreakdown {
+        let fiber = unsafe { self.get_inner() };
+        TimeBreakdown {
+            running_ns: fiber.running_ns,
+            pending_ns: fiber.pending_ns,
+        }
+    }
+
+    /// Tie `resource` to the Fiber: it is dropped along with the Fiber, when its last
+    /// handle is dropped, whatever state it is in
+    ///
+    /// Resources are dropped in the order they were attached, on the thread dropping
+    /// the handle.
+    pub fn attach_resource<T: Send + 'static>(&self, resource: T) {
+        unsafe {
+            self.get_inner().resources.lock().unwrap().push(Box::new(resource));
+        }
+    }
+
+    /// Get the stack pointers saved at the last yields of the Fiber, oldest first
+    ///
+    /// At most `YIELD_HISTORY_LEN` of them are kept. The stack grows down: the lower
+    /// the pointer, the deeper the Fiber was when it yielded.
+    #[cfg(feature = "yield-history")]
+    pub fn yield_history(&self) -> Vec<usize> {
+        let fiber = unsafe { self.get_inner() };
+        fiber.yield_history.iter().cloned().collect()
+    }
+
+    /// Subscribe to the state transitions of the Fiber
+    pub fn subscribe(&self) -> StateWatch {
+        let (tx, rx) = channel();
+        self.on_state_change(move|state| {
+            let _ = tx.send(state.clone());
+        });
+        StateWatch { rx: rx }
+    }
+
+    /// Register a callback that is called with the new state on each transition
+    ///
+    /// Observers are called in registration order, on the thread that changes the
+    /// state: the resuming one for `Running`, the Fiber itself for the states it
+    /// yields with. They must not register more observers on the same Fiber.
+    pub fn on_state_change<F>(&self, cb: F)
+        where F: Fn(&State) + Send + 'static
+    {
+        unsafe {
+            self.get_inner().observers.lock().unwrap().push(Box::new(cb));
+        }
+    }
 }
 
+/// Only valid while the Fiber is suspended, or from the Fiber itself: anywhere
+/// else, reading a Fiber that is running is a data race. Debug builds check this.
 impl Deref for Handle {
     type Target = Fiber;
 
     #[inline]
     fn deref(&self) -> &Fiber {
-        unsafe { self.get_inner() }
+        let fiber = unsafe { self.get_inner() };
+        debug_assert!(!fiber.running.load(Ordering::Acquire) || *self.0 == running_fiber(),
+                      "Fiber {:?} dereferenced while it is running", fiber.id);
+        fiber
+    }
+}
+
+/// Something that runs Fibers
+///
+/// All the work of switching into a Fiber, keeping track of the context to come
+/// back to, and of the Fiber or thread that drove it, is done by `drive`, so an
+/// implementation only has to decide which Fiber to drive next.
+///
+/// A Driver has to uphold the following:
+///
+/// * a Fiber is driven by one thread at a time; driving one that is already
+///   running elsewhere panics, see `Handle::resume` to find out beforehand;
+/// * a Fiber that returned `State::Pending` is only worth driving again once its
+///   signal is pulsed, driving it before just gives `Pending` back;
+/// * a Fiber that returned `State::Finished` or `State::Panicked` never runs again,
+///   it can be dropped or `reset`;
+/// * Fibers may be driven from inside other Fibers, but never from a signal
+///   handler.
+pub trait Driver {
+    /// Run `fiber` until it yields, and return its new state
+    fn drive(&mut self, fiber: &Handle) -> State {
+        fiber.run()
+    }
+}
+
+/// Subscription to the state transitions of a Fiber, see `Handle::subscribe`
+pub struct StateWatch {
+    rx: Receiver<State>,
+}
+
+impl StateWatch {
+    /// Block the current thread until the next transition of the Fiber, and return
+    /// the state it moved to
+    ///
+    /// Every transition since the subscription is reported once, in order, even if
+    /// several happened since the last call. Returns `None` once the Fiber is gone
+    /// and all its transitions have been reported.
+    pub fn changed(&mut self) -> Option<State> {
+        self.rx.recv().ok()
+    }
+
+    /// Return the next transition of the Fiber if there already is one
+    pub fn try_changed(&mut self) -> Option<State> {
+        self.rx.try_recv().ok()
+    }
+}
+
+/// Clonable handle of a Fiber, which can be shared between threads
+///
+/// All the clones refer to the same Fiber, which is freed once the last one is
+/// dropped. Only one of them can run the Fiber at a time: the others get
+/// `ResumeError::Busy` from `resume` meanwhile.
+#[derive(Clone)]
+pub struct SharedHandle(Arc<Handle>);
+
+unsafe impl Send for SharedHandle {}
+unsafe impl Sync for SharedHandle {}
+
+impl Debug for SharedHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Deref for SharedHandle {
+    type Target = Handle;
+
+    #[inline]
+    fn deref(&self) -> &Handle {
+        &*self.0
+    }
+}
+
+impl SharedHandle {
+    /// Get a reference to the Fiber that does not keep it alive
+    pub fn downgrade(&self) -> WeakHandle {
+        WeakHandle(self.0.downgrade())
+    }
+}
+
+/// Non-owning reference to a Fiber, see `SharedHandle::downgrade`
+///
+/// It does not keep the Fiber from being freed along with its last `SharedHandle`.
+#[derive(Clone)]
+pub struct WeakHandle(Weak<Handle>);
+
+unsafe impl Send for WeakHandle {}
+unsafe impl Sync for WeakHandle {}
+
+impl Debug for WeakHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.upgrade() {
+            Some(handle) => write!(f, "WeakHandle({:?})", handle),
+            None => write!(f, "WeakHandle(freed)"),
+        }
+    }
+}
+
+impl WeakHandle {
+    /// Get a handle of the Fiber, `None` if it was already freed
+    pub fn upgrade(&self) -> Option<SharedHandle> {
+        self.0.upgrade().map(SharedHandle)
     }
 }
 
 /// A coroutine is nothing more than a (register context, stack) pair.
-#[allow(raw_pointer_derive)]
-#[derive(Debug)]
 pub struct Fiber {
     /// The segment of stack on which the task is currently running or
     /// if the task is blocked, on which the task will resume
@@ -219,87 +878,502 @@ pub struct Fiber {
 
     /// Name
     name: Option<String>,
+
+    /// Set while a driver is running the Fiber
+    running: AtomicBool,
+
+    /// Identifier
+    id: FiberId,
+
+    /// Called when the Fiber ends
+    on_finish: Option<Box<FnMut(FinishReason) + Send>>,
+
+    /// When the timed wait of the Fiber gives up, in `time::precise_time_ns`
+    timeout_at: Option<u64>,
+
+    /// What the Fiber panicked with, until taken
+    panic: Option<Box<Any + Send>>,
+
+    /// Whether the panic message of the Fiber is reported, see `set_panic_sink`
+    print_panic: bool,
+
+    /// Whether a panic of the Fiber aborts the process
+    abort_on_panic: bool,
+
+    /// Set by `Handle::cancel`, until the Fiber is reset
+    cancelled: bool,
+
+    /// Whether the Fiber was already made to unwind for `cancelled`
+    unwinding: bool,
+
+    /// Whether dropping the last handle cancels the Fiber, see `Options::cancel_on_drop`
+    cancel_on_drop: bool,
+
+    /// Scheduler installed by default while the Fiber runs, see `Options::scheduler`
+    scheduler: Option<Arc<pulse::Scheduler + Send + Sync>>,
+
+    /// Whether the stack was filled for `Handle::stack_high_water`
+    stack_watermark: bool,
+
+    /// Tracing identifiers of the Fiber, given or inherited at spawn
+    trace_context: Option<TraceContext>,
+
+    /// Most `sched` yields a second, see `Options::max_switch_rate`
+    max_switch_rate: Option<u32>,
+
+    /// Whether the yields beyond `max_switch_rate` are skipped
+    coalesce_yields: bool,
+
+    /// When the current one-second window of `max_switch_rate` started, in
+    /// `time::precise_time_ns`, and the yields in it so far
+    switch_window: (u64, u32),
+
+    /// Whether the Fiber already got warned about its switch rate
+    switch_warned: bool,
+
+    /// Number of times the Fiber was switched into
+    resume_count: usize,
+
+    /// Stack pointers of the last yields, see `Handle::yield_history`
+    #[cfg(feature = "yield-history")]
+    yield_history: VecDeque<usize>,
+
+    /// Bytes between the end of the stack and its first frame, see `Options::stack_colors`
+    stack_offset: usize,
+
+    /// Called on each state transition
+    observers: Mutex<Vec<Box<Fn(&State) + Send>>>,
+
+    /// Last value given to `report_progress`
+    progress: Option<Box<Any + Send>>,
+
+    /// Run each time the Fiber suspends itself, see `on_yield`
+    yield_hooks: Vec<Box<FnMut() + Send>>,
+
+    /// Dropped along with the Fiber, see `Handle::attach_resource`
+    resources: Mutex<Vec<Box<Any + Send>>>,
+
+    /// Fiber-local values by type, see `with_local`. None while borrowed.
+    locals: HashMap<TypeId, Option<Box<Any + Send>>>,
+
+    /// Time spent in each state, see `TimeBreakdown`
+    running_ns: u64,
+    pending_ns: u64,
+
+    /// When the state last changed, in `time::precise_time_ns`
+    switched_at: u64,
 }
 
 unsafe impl Send for Fiber {}
 
+impl Debug for Fiber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Fiber")
+            .field("current_stack_segment", &self.current_stack_segment)
+            .field("saved_context", &self.saved_context)
+            .field("state", &self.state)
+            .field("name", &self.name)
+            .field("running", &self.running.load(Ordering::Relaxed))
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+/// What a Fiber unwinds with when it is cancelled, see `Handle::cancel`
+struct Cancelled;
+
 /// Initialization function for make context
+///
+/// The closure is consumed by `invoke`, so its captures are dropped right here on
+/// the Fiber's own stack, whether it returns or unwinds, and so are the yield hooks
+/// and `on_finish` after it. Only then does the Fiber switch out for the last time:
+/// by the time its stack can be reused by `reset`, or given back to a pool or
+/// unmapped when the Fiber is dropped, no user destructor is left to run on it.
 extern "C" fn coroutine_initialize(_: usize, f: *mut ()) -> ! {
     let func: Box<Thunk> = unsafe { transmute(f) };
 
-    let ret = unsafe { try(move|| func.invoke(())) };
+    let ret = if unsafe { (*running_fiber()).cancelled } {
+        // Cancelled before it ever ran
+        drop(func);
+        Err(Box::new(Cancelled) as Box<Any + Send>)
+    } else {
+        unsafe { try(move|| func.invoke(())) }
+    };
 
+    let mut cancelled = false;
     let state = match ret {
         Ok(..) => State::Finished,
+        Err(ref err) if err.downcast_ref::<Cancelled>().is_some() => {
+            cancelled = true;
+            State::Finished
+        }
         Err(err) => {
-            {
-                use std::io::stderr;
-                use std::io::Write;
-                let msg = match err.downcast_ref::<&'static str>() {
-                    Some(s) => *s,
-                    None => match err.downcast_ref::<String>() {
-                        Some(s) => &s[..],
-                        None => "Box<Any>",
-                    }
-                };
-
-                let _ = writeln!(&mut stderr(), "Fiber panicked at '{}'", msg);
+            let fiber = unsafe { &mut *running_fiber() };
+            if fiber.print_panic || fiber.abort_on_panic {
+                report_panic(&*err);
             }
+            if fiber.abort_on_panic {
+                unsafe { libc::abort() }
+            }
+            fiber.panic = Some(err);
             State::Panicked
         }
     };
 
+    // Nothing may unwind past this frame, the callbacks included
+    let hooks = unsafe { mem::replace(&mut (*running_fiber()).yield_hooks, Vec::new()) };
+    let _ = unsafe { try(move|| drop(hooks)) };
+
+    let reason = if cancelled {
+        FinishReason::Cancelled
+    } else if state.is_panic() {
+        FinishReason::Panicked
+    } else {
+        FinishReason::Finished
+    };
+    let on_finish = unsafe { (*running_fiber()).on_finish.take() };
+    if let Some(mut on_finish) = on_finish {
+        let _ = unsafe { try(move|| on_finish(reason)) };
+    }
+
     Fiber::yield_now(state);
     unreachable!()
 }
 
+lazy_static! {
+    static ref PANIC_SINK: Mutex<Option<Arc<Fn(&str) + Send + Sync>>> = Mutex::new(None);
+    static ref PANIC_HOOK: Mutex<Option<Arc<Fn(&Any) + Send + Sync>>> = Mutex::new(None);
+}
+
+/// Set where the messages of panicking Fibers go
+///
+/// Without a sink, the messages are logged with `error!`, or written to stderr if
+/// no logger is enabled for errors.
+pub fn set_panic_sink<F>(sink: F)
+    where F: Fn(&str) + Send + Sync + 'static
+{
+    *PANIC_SINK.lock().unwrap() = Some(Arc::new(sink));
+}
+
+/// Set what is called with the payload of each panicking Fiber, instead of reporting
+/// its message, replacing any previous hook
+///
+/// The hook takes precedence over the sink of `set_panic_sink`. It is only called
+/// for the Fibers whose panics are reported, see `Options::print_panic`, and runs on
+/// the panicking Fiber. A panic of the hook itself, or of the sink, is written to
+/// stderr and otherwise ignored.
+pub fn set_panic_hook<F>(hook: F)
+    where F: Fn(&Any) + Send + Sync + 'static
+{
+    *PANIC_HOOK.lock().unwrap() = Some(Arc::new(hook));
+}
+
+/// Remove the hook set with `set_panic_hook`, going back to reporting the messages
+pub fn take_panic_hook() -> Option<Arc<Fn(&Any) + Send + Sync>> {
+    PANIC_HOOK.lock().unwrap().take()
+}
+
+fn report_panic(payload: &Any) {
+    // Called without holding the locks, the hook and sink may well spawn, run or
+    // panic Fibers of their own
+    let hook = PANIC_HOOK.lock().unwrap().clone();
+    let sink = PANIC_SINK.lock().unwrap().clone();
+
+    let reported = unsafe { try(move|| {
+        if let Some(hook) = hook {
+            hook(payload);
+            return;
+        }
+
+        let msg = match payload.downcast_ref::<&'static str>() {
+            Some(s) => *s,
+            None => match payload.downcast_ref::<String>() {
+                Some(s) => &s[..],
+                None => "Box<Any>",
+            }
+        };
+        match sink {
+            Some(sink) => sink(msg),
+            None if log_enabled!(LogLevel::Error) => error!("Fiber panicked at '{}'", msg),
+            None => {
+                use std::io::stderr;
+                use std::io::Write;
+                let _ = writeln!(&mut stderr(), "Fiber panicked at '{}'", msg);
+            }
+        }
+    }) };
+    if reported.is_err() {
+        use std::io::stderr;
+        use std::io::Write;
+        let _ = writeln!(&mut stderr(), "Fiber panic hook panicked");
+    }
+}
+
 impl Fiber {
-    fn new(name: Option<String>,
+    fn new(opts: Options,
            stack: Stack,
            ctx: Context,
+           stack_offset: usize,
            state: State) -> Handle {
 
         Handle::new(Fiber {
             current_stack_segment: Some(stack),
             saved_context: ctx,
             state: state,
-            name: name
+            name: opts.name,
+            running: AtomicBool::new(false),
+            id: FiberId(NEXT_FIBER_ID.fetch_add(1, Ordering::Relaxed)),
+            on_finish: opts.on_finish,
+            timeout_at: None,
+            panic: None,
+            print_panic: opts.print_panic,
+            abort_on_panic: opts.abort_on_panic,
+            cancelled: false,
+            unwinding: false,
+            cancel_on_drop: opts.cancel_on_drop,
+            scheduler: opts.scheduler,
+            stack_watermark: opts.stack_watermark,
+            trace_context: opts.trace_context.or_else(trace_context),
+            max_switch_rate: opts.max_switch_rate,
+            coalesce_yields: opts.coalesce_yields,
+            switch_window: (0, 0),
+            switch_warned: false,
+            resume_count: 0,
+            stack_offset: stack_offset,
+            #[cfg(feature = "yield-history")]
+            yield_history: VecDeque::with_capacity(YIELD_HISTORY_LEN),
+            observers: Mutex::new(Vec::new()),
+            progress: None,
+            yield_hooks: Vec::new(),
+            resources: Mutex::new(Vec::new()),
+            locals: HashMap::new(),
+            running_ns: 0,
+            pending_ns: 0,
+            switched_at: time::precise_time_ns(),
         })
     }
 
+    /// Remember where the stack of the Fiber was when it yielded, if it just did
+    #[cfg(feature = "yield-history")]
+    fn record_yield(&mut self) {
+        if self.state.is_pending() {
+            if self.yield_history.len() == YIELD_HISTORY_LEN {
+                self.yield_history.pop_front();
+            }
+            let sp = self.saved_context.stack_pointer();
+            self.yield_history.push_back(sp);
+        }
+    }
+
+    #[cfg(not(feature = "yield-history"))]
+    #[inline(always)]
+    fn record_yield(&mut self) {}
+
+    fn set_state(&mut self, state: State) {
+        let now = time::precise_time_ns();
+        let elapsed = now - self.switched_at;
+        match self.state {
+            State::Running => self.running_ns += elapsed,
+            State::Pending(_) | State::PendingTimeout(_, _) => self.pending_ns += elapsed,
+            State::Finished | State::Panicked => (),
+        }
+        self.switched_at = now;
+
+        self.state = state;
+        for observer in self.observers.lock().unwrap().iter() {
+            observer(&self.state);
+        }
+    }
+
     fn yield_now(state: State) {
         let parent: &mut Parent = PARENT_CONTEXT.with(|pctx| {
             unsafe { transmute(*pctx.get()) }
         });
         unsafe {
-            (*parent.running).state = state;
+            if state.is_pending() {
+                (*parent.running).run_yield_hooks();
+            }
+            (*parent.running).set_state(state);
             Context::swap(&mut (*parent.running).saved_context, &parent.context);
         }
+
+        // Switched back in by `Handle::cancel`
+        let fiber = unsafe { &mut *running_fiber() };
+        if fiber.cancelled && !fiber.unwinding {
+            fiber.unwinding = true;
+            panic!(Cancelled);
+        }
+    }
+
+    fn run_yield_hooks(&mut self) {
+        // Hooks may register more hooks, which first run at the next yield
+        let mut hooks = mem::replace(&mut self.yield_hooks, Vec::new());
+        for hook in hooks.iter_mut() {
+            hook();
+        }
+        let added = mem::replace(&mut self.yield_hooks, hooks);
+        self.yield_hooks.extend(added.into_iter());
+    }
+
+    /// Count a `sched` yield against `max_switch_rate`, and tell whether it is to be
+    /// skipped
+    fn over_switch_rate(&mut self) -> bool {
+        let rate = match self.max_switch_rate {
+            Some(rate) => rate,
+            None => return false,
+        };
+        let now = time::precise_time_ns();
+        if now - self.switch_window.0 >= 1000000000 {
+            self.switch_window = (now, 0);
+        }
+        self.switch_window.1 += 1;
+        if self.switch_window.1 <= rate {
+            return false;
+        }
+        if !self.switch_warned {
+            self.switch_warned = true;
+            warn!("Fiber {:?} ({:?}) yields more than {} times a second",
+                  self.name, self.id, rate);
+        }
+        self.coalesce_yields
+    }
+
+    /// Yield the current Fiber, letting its driver run something else
+    ///
+    /// The Fiber is ready to run again right away. Outside of a Fiber, this does what
+    /// the `SchedPolicy` set with `set_sched_policy` says.
+    pub fn sched() {
+        let fiber = running_fiber();
+        if !fiber.is_null() {
+            if unsafe { (*fiber).over_switch_rate() } {
+                return;
+            }
+            Fiber::yield_now(State::Pending(Signal::pulsed()));
+            return;
+        }
+        match sched_policy() {
+            SchedPolicy::Ignore => (),
+            SchedPolicy::Yield => thread::yield_now(),
+            SchedPolicy::Panic => panic!("Fiber::sched called outside of a Fiber"),
+        }
+    }
+
+    /// Yield the current Fiber until it is resumed with a value, see
+    /// `Handle::resume_with`, and return that value
+    ///
+    /// Panics if the Fiber is resumed with `resume` or with a value of another type,
+    /// and outside of a Fiber.
+    pub fn sched_value<T: Any>() -> T {
+        assert!(!running_fiber().is_null(), "sched_value called outside of a Fiber");
+        Fiber::yield_now(State::Pending(Signal::pulsed()));
+
+        let parent: &mut Parent = PARENT_CONTEXT.with(|pctx| {
+            unsafe { transmute(*pctx.get()) }
+        });
+        match unsafe { take_slot::<T>(&mut parent.input) } {
+            Ok(Some(val)) => val,
+            Ok(None) => panic!("sched_value resumed without a value"),
+            Err(()) => panic!("sched_value resumed with a value of another type"),
+        }
+    }
+
+    /// Yield the current Fiber, giving `val` to its driver, see `Handle::resume_yield`
+    ///
+    /// `val` stays on the stack of the Fiber until the driver takes it; if the driver
+    /// does not, it is dropped when the Fiber is resumed. Panics outside of a Fiber.
+    pub fn yield_value<T: Any + Send>(val: T) {
+        assert!(!running_fiber().is_null(), "yield_value called outside of a Fiber");
+        let mut slot = Some(val);
+
+        let parent: &mut Parent = PARENT_CONTEXT.with(|pctx| {
+            unsafe { transmute(*pctx.get()) }
+        });
+        parent.output = Some((TypeId::of::<T>(), &mut slot as *mut Option<T> as *mut ()));
+        Fiber::yield_now(State::Pending(Signal::pulsed()));
+    }
+
+    /// Get the Handle of the current Fiber
+    ///
+    /// The reference is only valid while the Fiber runs: it must not be kept across
+    /// yields, as the Fiber may be resumed through another Handle or the Handle may
+    /// be gone. Panics outside of a Fiber.
+    pub fn current() -> &'static Handle {
+        PARENT_CONTEXT.with(|pctx| unsafe {
+            let parent = *pctx.get();
+            assert!(!parent.is_null(), "Fiber::current called outside of a Fiber");
+            &*(*parent).handle
+        })
     }
 
     /// Spawn a Fiber with options
     pub fn spawn_opts<F>(f: F, opts: Options) -> Handle
         where F: FnOnce() + Send + 'static
     {
-        let mut stack = Stack::new(1024*1024);
-        let ctx = Context::new(coroutine_initialize, 0, f, &mut stack);
-        Fiber::new(opts.name, stack, ctx, State::Pending(Signal::pulsed()))
+        spawned(Fiber::try_spawn_opts(f, opts))
+    }
+
+    /// Spawn a Fiber with options, failing instead of panicking if it can't be set up
+    pub fn try_spawn_opts<F>(f: F, opts: Options) -> Result<Handle, SpawnError>
+        where F: FnOnce() + Send + 'static
+    {
+        if in_signal_context() {
+            return Err(SpawnError::InSignalHandler);
+        }
+        let size = if opts.size_heuristic {
+            heuristic_stack_size::<F>(clamp_stack_size(opts.stack_size))
+        } else {
+            clamp_stack_size(opts.stack_size)
+        };
+        let mut stack = if let Some(ref arena) = opts.stack_arena {
+            try!(arena.try_take_stack(size, opts.guard_pages))
+        } else if opts.pool_stack {
+            try!(StackPool::thread_default().try_take_stack_with_guard(size, opts.guard_pages))
+        } else {
+            try!(Stack::try_with_guard(size, opts.guard_pages))
+        };
+        if opts.stack_watermark {
+            stack.fill_watermark();
+        }
+        let offset = stack_color_offset(opts.stack_colors, &stack);
+        let ctx = Context::with_offset(coroutine_initialize, 0, f, &mut stack, offset);
+        check_initial_sp(&ctx, &stack);
+        Ok(Fiber::new(opts, stack, ctx, offset, State::Pending(Signal::pulsed())))
     }
 
     /// Spawn a Fiber with default options
     pub fn spawn<F>(f: F) -> Handle
         where F: FnOnce() + Send + 'static
     {
-        Fiber::spawn_opts(f, Default::default())
+        spawned(Fiber::try_spawn(f))
+    }
+
+    /// Spawn a Fiber with default options, failing instead of panicking if it can't
+    /// be set up
+    pub fn try_spawn<F>(f: F) -> Result<Handle, SpawnError>
+        where F: FnOnce() + Send + 'static
+    {
+        Fiber::try_spawn_opts(f, Default::default())
     }
 
     /// Spawn a Fiber with options
-    pub fn spawn_with<F>(f: F, pool: super::StackPool) -> Handle
+    pub fn spawn_with<F>(f: F, pool: StackPool) -> Handle
+        where F: FnOnce() + Send + 'static
+    {
+        spawned(Fiber::try_spawn_with(f, pool))
+    }
+
+    /// Spawn a Fiber taking its stack from `pool`, failing instead of panicking if it
+    /// can't be set up
+    pub fn try_spawn_with<F>(f: F, pool: StackPool) -> Result<Handle, SpawnError>
         where F: FnOnce() + Send + 'static
     {
-        let mut stack = pool.take_stack(1024*1024);
+        if in_signal_context() {
+            return Err(SpawnError::InSignalHandler);
+        }
+        let mut stack = try!(pool.try_take_stack(DEFAULT_STACK_SIZE));
         let ctx = Context::new(coroutine_initialize, 0, f, &mut stack);
-        Fiber::new(None, stack, ctx, State::Pending(Signal::pulsed()))
+        check_initial_sp(&ctx, &stack);
+        Ok(Fiber::new(Default::default(), stack, ctx, 0, State::Pending(Signal::pulsed())))
     }
 
     #[inline(always)]
@@ -313,6 +1387,27 @@ impl Fiber {
         self.name.as_ref().map(|s| &**s)
     }
 
+    /// Get the identifier of the Fiber
+    #[inline(always)]
+    pub fn id(&self) -> FiberId {
+        self.id
+    }
+
+    /// Get the number of times the Fiber was switched into
+    ///
+    /// Resuming a Fiber whose signal is still pending does not count.
+    #[inline(always)]
+    pub fn resume_count(&self) -> usize {
+        self.resume_count
+    }
+
+    /// Get the size of the stack of the Fiber, guard page included
+    pub fn stack_size(&self) -> usize {
+        self.current_stack_segment.as_ref()
+            .map(|s| s.end() as usize - s.start() as usize)
+            .unwrap_or(0)
+    }
+
     /// Determines whether the current Fiber is unwinding because of panic.
     #[inline(always)]
     pub fn panicking(&self) -> bool {
@@ -332,22 +1427,442 @@ impl Fiber {
     }
 }
 
+/// Distance between two stack colors, a cache line
+///
+/// Stacks are page aligned, so without coloring the hottest frames of every Fiber
+/// map to the same cache sets. Coloring moves the first frame of each new Fiber
+/// down by a multiple of this, cycling through the colors.
+const STACK_COLOR_STRIDE: usize = 64;
+
+static NEXT_STACK_COLOR: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Offset of the first frame of a new Fiber from the end of `stack`
+fn stack_color_offset(colors: usize, stack: &Stack) -> usize {
+    if colors <= 1 {
+        return 0;
+    }
+    let color = NEXT_STACK_COLOR.fetch_add(1, Ordering::Relaxed) % colors;
+    // Never eat more than a quarter of the stack
+    let usable = (stack.end() as usize - stack.guard() as usize) / 4;
+    cmp::min(color * STACK_COLOR_STRIDE, usable / STACK_COLOR_STRIDE * STACK_COLOR_STRIDE)
+}
+
+/// Bytes a new Fiber needs between its initial stack pointer and the guard page,
+/// for the frame of `coroutine_initialize`
+const INITIAL_FRAME_ROOM: usize = 256;
+
+/// Check that the initial stack pointer of `ctx` lies within `stack`, above the
+/// guard page with room for the first frame, in debug builds
+///
+/// Getting it wrong would have the first frame written outside of the stack.
+fn check_initial_sp(ctx: &Context, stack: &Stack) {
+    let sp = ctx.stack_pointer();
+    let (lo, hi) = (stack.guard() as usize, stack.end() as usize);
+    debug_assert!(sp <= hi && sp >= lo + INITIAL_FRAME_ROOM,
+                  "initial stack pointer {:#x} is out of the stack ({:#x}, {:#x}]", sp, lo, hi);
+}
+
+/// Size of the stack of a Fiber that doesn't ask for one
+const DEFAULT_STACK_SIZE: usize = 1024 * 1024;
+
+/// Smallest stack a Fiber gets, in pages, the guard page included
+///
+/// `min_stack()` is no floor here: it is the size of the main stacks of threads,
+/// 2MiB unless `RUST_MIN_STACK` says otherwise, and much more than a Fiber needs.
+const MIN_STACK_PAGES: usize = 16;
+
+/// The size of the stack mapped for a requested `size`
+fn clamp_stack_size(size: usize) -> usize {
+    cmp::max(size, MIN_STACK_PAGES * page_size())
+}
+
+/// Largest stack `heuristic_stack_size` asks for
+const HEURISTIC_MAX_STACK: usize = 64 * 1024 * 1024;
+
+/// Stack size for a Fiber running a closure of type `F`, at least `base`
+///
+/// The captures are moved a few times on their way to the body, each move
+/// possibly leaving a copy in a frame of the Fiber's stack, so room for four of
+/// them is added on top of `base`, up to `HEURISTIC_MAX_STACK`. This is only a
+/// heuristic: it knows nothing about what the body itself puts on the stack.
+fn heuristic_stack_size<F>(base: usize) -> usize {
+    let extra = mem::size_of::<F>().checked_mul(4).unwrap_or(HEURISTIC_MAX_STACK);
+    let page = page_size();
+    let size = cmp::min(base.saturating_add(extra), HEURISTIC_MAX_STACK);
+    let size = (size + page - 1) / page * page;
+    cmp::max(base, size)
+}
+
+/// Number of yields `Handle::yield_history` remembers
+#[cfg(feature = "yield-history")]
+pub const YIELD_HISTORY_LEN: usize = 64;
+
+/// Unwrap the result of a spawn, for the panicking flavours
+fn spawned(res: Result<Handle, SpawnError>) -> Handle {
+    match res {
+        Ok(handle) => handle,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+/// A value passed between a Fiber and its driver: the type of the value, and a
+/// pointer to an `Option` of that type on the stack of the side giving it
+type Slot = Option<(TypeId, *mut ())>;
+
+/// Take the value out of `slot`, if it holds one of type `T`
+///
+/// Returns `Err` if `slot` holds a value of another type.
+unsafe fn take_slot<T: Any>(slot: &mut Slot) -> Result<Option<T>, ()> {
+    match slot.take() {
+        Some((id, ptr)) if id == TypeId::of::<T>() => Ok((*(ptr as *mut Option<T>)).take()),
+        Some(other) => {
+            *slot = Some(other);
+            Err(())
+        }
+        None => Ok(None),
+    }
+}
+
 struct Parent {
     context: Context,
-    running: *mut Fiber
+    running: *mut Fiber,
+    /// Parent of the driver itself, null if the driver is not a Fiber
+    outer: *mut Parent,
+    /// Given by the driver with `resume_with`
+    input: Slot,
+    /// Given by the Fiber with `yield_value`, pointing into its own stack
+    output: Slot,
+    /// The Handle the Fiber is being resumed through
+    handle: *const Handle,
 }
 
 thread_local!(static PARENT_CONTEXT: UnsafeCell<*mut Parent> = UnsafeCell::new(ptr::null_mut()));
 
+/// Check every link of the chain of Fibers running on the current thread, from the
+/// innermost one out: each must be marked as running, in the `Running` state, and
+/// appear only once
+#[cfg(feature = "test-support")]
+#[doc(hidden)]
+pub fn check_parent_chain() -> Result<(), String> {
+    let mut seen: Vec<*mut Parent> = Vec::new();
+    let mut parent = PARENT_CONTEXT.with(|pctx| unsafe { *pctx.get() });
+    while !parent.is_null() {
+        if seen.contains(&parent) {
+            return Err(format!("parent chain loops after {} links", seen.len()));
+        }
+        seen.push(parent);
+
+        let fiber = unsafe { &*(*parent).running };
+        if !fiber.running.load(Ordering::Acquire) {
+            return Err(format!("Fiber {:?} is in the parent chain but not marked running", fiber.id));
+        }
+        if !fiber.state.is_running() {
+            return Err(format!("Fiber {:?} is in the parent chain in state {:?}", fiber.id, fiber.state));
+        }
+        parent = unsafe { (*parent).outer };
+    }
+    Ok(())
+}
+
+#[cfg(feature = "test-support")]
+impl Handle {
+    #[doc(hidden)]
+    pub fn check_stack_intact(&self) -> Result<(), String> {
+        let fiber = unsafe { self.get_inner() };
+        match fiber.current_stack_segment {
+            Some(ref stack) => stack.check_intact(),
+            None => Ok(()),
+        }
+    }
+
+    #[doc(hidden)]
+    pub fn stack_guard(&self) -> *const usize {
+        let fiber = unsafe { self.get_inner() };
+        fiber.current_stack_segment.as_ref().map(|s| s.guard()).unwrap_or(ptr::null())
+    }
+}
+
+/// Report the progress of the current Fiber to its driver, see
+/// `Handle::resume_with_progress`
+///
+/// Only the last value reported before the driver looks is kept.
+///
+/// Panics outside of a Fiber.
+pub fn report_progress<P: Any + Send>(progress: P) {
+    let fiber = running_fiber();
+    assert!(!fiber.is_null(), "report_progress called outside of a Fiber");
+    unsafe {
+        (*fiber).progress = Some(Box::new(progress));
+    }
+}
+
+/// What `Fiber::sched` does when called outside of a Fiber
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedPolicy {
+    /// Return right away, the default
+    Ignore,
+
+    /// Yield the OS thread with `thread::yield_now`
+    Yield,
+
+    /// Panic
+    Panic,
+}
+
+static SCHED_POLICY: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Set what `Fiber::sched` does when called outside of a Fiber, for the whole process
+pub fn set_sched_policy(policy: SchedPolicy) {
+    let raw = match policy {
+        SchedPolicy::Ignore => 0,
+        SchedPolicy::Yield => 1,
+        SchedPolicy::Panic => 2,
+    };
+    SCHED_POLICY.store(raw, Ordering::Relaxed);
+}
+
+/// Get what `Fiber::sched` does when called outside of a Fiber
+pub fn sched_policy() -> SchedPolicy {
+    match SCHED_POLICY.load(Ordering::Relaxed) {
+        0 => SchedPolicy::Ignore,
+        1 => SchedPolicy::Yield,
+        _ => SchedPolicy::Panic,
+    }
+}
+
+/// Perform an effect: suspend the current Fiber, handing `req` to its driver, and
+/// return the response the driver resumes it with, see `Handle::resume_effect`
+///
+/// Panics if the Fiber is resumed without a response, or with a response of another
+/// type than `Resp`, and outside of a Fiber.
+pub fn perform<Req: Any + Send, Resp: Any>(req: Req) -> Resp {
+    assert!(!running_fiber().is_null(), "perform called outside of a Fiber");
+    let mut slot = Some(req);
+
+    let parent: &mut Parent = PARENT_CONTEXT.with(|pctx| {
+        unsafe { transmute(*pctx.get()) }
+    });
+    parent.output = Some((TypeId::of::<Req>(), &mut slot as *mut Option<Req> as *mut ()));
+    Fiber::yield_now(State::Pending(Signal::pulsed()));
+
+    // Resumed by another driver, maybe
+    let parent: &mut Parent = PARENT_CONTEXT.with(|pctx| {
+        unsafe { transmute(*pctx.get()) }
+    });
+    match unsafe { take_slot::<Resp>(&mut parent.input) } {
+        Ok(Some(resp)) => resp,
+        Ok(None) => panic!("perform resumed without a response"),
+        Err(()) => panic!("perform resumed with a response of another type"),
+    }
+}
+
+/// Register a callback that runs each time the current Fiber suspends itself
+///
+/// Callbacks run in registration order, on the Fiber, right before it switches
+/// out: in `Fiber::sched` and whenever it waits on a signal. They are dropped once
+/// the Fiber finishes. A panic in a callback unwinds the Fiber from the yield point.
+///
+/// Panics outside of a Fiber.
+pub fn on_yield<F>(cb: F)
+    where F: FnMut() + Send + 'static
+{
+    let fiber = running_fiber();
+    assert!(!fiber.is_null(), "on_yield called outside of a Fiber");
+    unsafe {
+        (*fiber).yield_hooks.push(Box::new(cb));
+    }
+}
+
+/// Pointer to the Fiber running on the current thread, null outside of any Fiber
+fn running_fiber() -> *mut Fiber {
+    PARENT_CONTEXT.with(|pctx| unsafe {
+        let parent = *pctx.get();
+        if parent.is_null() {
+            ptr::null_mut()
+        } else {
+            (*parent).running
+        }
+    })
+}
+
+fn noop() {}
+
+/// Spawn a Fiber whose body does nothing
+///
+/// Its body is a plain function, so the thunk needs no allocation: running it
+/// costs little more than the two `Context::swap`s in and out. Meant for
+/// measuring the switching cost itself.
+pub fn spawn_noop() -> Handle {
+    Fiber::spawn(noop)
+}
+
+/// Get the identifier of the current Fiber, `None` outside of any Fiber
+///
+/// Unlike the identity of the current thread, this stays the same when the Fiber is
+/// resumed by another thread than before, so code running in Fibers should prefer it
+/// for anything keyed by "who is running".
+pub fn identity() -> Option<FiberId> {
+    let fiber = running_fiber();
+    if fiber.is_null() {
+        None
+    } else {
+        Some(unsafe { (*fiber).id })
+    }
+}
+
+/// Get the tracing identifiers of the current Fiber, `None` outside of any Fiber or
+/// if it has none
+///
+/// Fibers spawned without `Options::trace_context` inherit the identifiers of the
+/// Fiber they are spawned from, so they follow a request down through its Fibers.
+pub fn trace_context() -> Option<TraceContext> {
+    let fiber = running_fiber();
+    if fiber.is_null() {
+        None
+    } else {
+        unsafe { (*fiber).trace_context }
+    }
+}
+
+/// Wait for the first of `signals` to be pulsed, or dropped, and return its index
+///
+/// A Fiber is suspended on a signal combining all of them, so its driver can run
+/// something else meanwhile. If several are ready already, the first one wins.
+/// Outside of a Fiber, this blocks the thread. Panics if `signals` is empty.
+pub fn select(signals: &[Signal]) -> usize {
+    assert!(!signals.is_empty(), "select over no signals");
+    if let Some(idx) = signals.iter().position(|s| !s.is_pending()) {
+        return idx;
+    }
+
+    let mut select = pulse::Select::new();
+    for signal in signals.iter() {
+        select.add(signal.clone());
+    }
+    // Waits on the combined signal, through the scheduler
+    let ready = select.next().unwrap();
+    signals.iter().position(|s| s.id() == ready.id()).unwrap()
+}
+
+/// Run `f` with the value of type `T` local to the current Fiber
+///
+/// Each Fiber has its own value of each type, created with `T::default()` on first
+/// use, which follows it from one thread to another. The values are dropped along
+/// with the Fiber, or when it is reset. Panics outside of a Fiber, and when nested
+/// for the same `T`; the value is kept even if `f` panics.
+pub fn with_local<T, F, R>(f: F) -> R
+    where T: Any + Send + Default, F: FnOnce(&mut T) -> R
+{
+    let fiber = running_fiber();
+    assert!(!fiber.is_null(), "with_local called outside of a Fiber");
+
+    let key = TypeId::of::<T>();
+    let taken = unsafe { (*fiber).locals.entry(key).or_insert_with(|| Some(Box::new(T::default()))).take() };
+    let mut local = match taken {
+        Some(value) => LocalGuard(key, Some(value)),
+        None => panic!("fiber-local value is already in use"),
+    };
+    f(local.1.as_mut().unwrap().downcast_mut::<T>().unwrap())
+}
+
+/// Puts a fiber-local value back when `with_local` returns or unwinds
+struct LocalGuard(TypeId, Option<Box<Any + Send>>);
 
-/// This is the `default` system scheduler that is used if no
-/// user provided scheduler is installed. It is very basic
-/// and will block the OS thread using `thread::park`
+impl Drop for LocalGuard {
+    fn drop(&mut self) {
+        // The Fiber may have moved to another thread meanwhile, but not to another Fiber
+        unsafe { (*running_fiber()).locals.insert(self.0, self.1.take()); }
+    }
+}
+
+thread_local!(static SIGNAL_DEPTH: Cell<usize> = Cell::new(0));
+
+/// Marks the current thread as running a signal handler, until dropped, see
+/// `enter_signal_context`
+pub struct SignalContext(());
+
+impl Drop for SignalContext {
+    fn drop(&mut self) {
+        SIGNAL_DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+/// Mark the current thread as running a signal handler, for as long as the returned
+/// guard lives
+///
+/// Spawning a Fiber and taking a stack from a pool allocate and lock: from a
+/// handler that interrupted the same work they can deadlock. While marked, they
+/// fail with `SpawnError::InSignalHandler` instead. Handlers that may reach them
+/// should hold the guard.
+pub fn enter_signal_context() -> SignalContext {
+    SIGNAL_DEPTH.with(|depth| depth.set(depth.get() + 1));
+    SignalContext(())
+}
+
+/// Whether the current thread is marked as running a signal handler
+pub fn in_signal_context() -> bool {
+    SIGNAL_DEPTH.with(|depth| depth.get() > 0)
+}
+
+/// Install a SIGSEGV/SIGBUS handler that reports faults in the guard pages of Fiber
+/// stacks as "fiber stack overflow", then aborts, instead of a plain crash
+///
+/// Only the stacks mapped after this call are known to the handler, and other
+/// faults go on to the previous handler. Each thread gets a signal stack the first
+/// time it resumes a Fiber, as the handler can't run on the stack that overflowed.
+/// Returns false where this is not supported, Linux on x86_64 and aarch64 only.
+pub fn report_stack_overflows() -> bool {
+    sys::overflow::install()
+}
+
+/// Batch the unmapping of the stacks of unpooled Fibers by `batch`, 0 to unmap them
+/// as soon as their Fiber is dropped, see `flush_deferred_unmaps`
+pub fn set_unmap_batch(batch: usize) {
+    stack::set_unmap_batch(batch)
+}
+
+/// Unmap the stacks of dropped unpooled Fibers that are waiting for their batch to
+/// fill up
+pub fn flush_deferred_unmaps() {
+    stack::flush_unmaps()
+}
+
+/// Determines whether the current Fiber is run directly by an OS thread
+///
+/// Returns `false` inside of a Fiber that was resumed by another Fiber, and outside
+/// of any Fiber.
+pub fn is_root() -> bool {
+    PARENT_CONTEXT.with(|pctx| unsafe {
+        let parent = *pctx.get();
+        !parent.is_null() && (*parent).outer.is_null()
+    })
+}
+
+/// The scheduler installed while a Fiber runs: waiting on a signal from inside a
+/// Fiber does not block the OS thread, the Fiber yields `State::Pending` with the
+/// signal back to its driver instead
+///
+/// No wakeup can be lost between the Fiber seeing the signal pending and yielding:
+/// the driver only switches the Fiber back in after seeing the signal pulsed, and
+/// both sides check the state of the signal itself, not a copy of it. A driver
+/// with nothing else to do can block on the signal it got back, with `wait`
+/// outside of any Fiber, which parks the OS thread until the signal is pulsed
+/// from wherever.
+///
+/// A Fiber in a timed wait, `State::PendingTimeout`, is pinned to the thread it
+/// started the wait on: pulse keeps a borrow of that thread's scheduler across the
+/// wait, so resuming it from another thread panics it. On a thread that is not
+/// running a Fiber, waits block the thread like `pulse::ThreadScheduler`.
 #[derive(Debug)]
 pub struct Resume;
 
 impl pulse::Scheduler for Resume {
     fn wait(&self, signal: Signal) -> Result<(), pulse::WaitError> {
+        if running_fiber().is_null() {
+            // Installed on a thread that is not running a Fiber right now
+            return pulse::Scheduler::wait(&pulse::ThreadScheduler, signal);
+        }
+        let _waiter = Waiter::register(&signal);
         loop {
             match signal.state() {
                 pulse::SignalState::Pending => {
@@ -359,7 +1874,122 @@ impl pulse::Scheduler for Resume {
         }
     }
 
-    fn wait_timeout_ms(&self, _: Signal, _: u32) -> Result<(), pulse::TimeoutError> {
-        panic!("unsupported yet")
+    fn wait_timeout_ms(&self, signal: Signal, ms: u32) -> Result<(), pulse::TimeoutError> {
+        if running_fiber().is_null() {
+            return pulse::Scheduler::wait_timeout_ms(&pulse::ThreadScheduler, signal, ms);
+        }
+        let _waiter = Waiter::register(&signal);
+        // pulse runs timed waits from within a borrow of the scheduler of the thread
+        // they started on, which must still be the one the Fiber runs on when it
+        // returns
+        let thread = PARENT_CONTEXT.with(|pctx| pctx as *const _ as usize);
+        let deadline = time::precise_time_ns() + ms as u64 * 1000000;
+        let res;
+        loop {
+            match signal.state() {
+                pulse::SignalState::Pending => {
+                    let now = time::precise_time_ns();
+                    if now >= deadline {
+                        res = Err(pulse::TimeoutError::Timeout);
+                        break;
+                    }
+                    unsafe { (*running_fiber()).timeout_at = Some(deadline); }
+                    let left_ms = ((deadline - now + 999999) / 1000000) as u32;
+                    Fiber::yield_now(State::PendingTimeout(signal.clone(), left_ms));
+                    assert!(PARENT_CONTEXT.with(|pctx| pctx as *const _ as usize) == thread,
+                            "Fiber resumed on another thread during a timed wait");
+                }
+                pulse::SignalState::Pulsed => { res = Ok(()); break; }
+                pulse::SignalState::Dropped => { res = Err(pulse::TimeoutError::Error(pulse::WaitError::Dropped)); break; }
+            }
+        }
+        unsafe { (*running_fiber()).timeout_at = None; }
+        res
     }
-}
\ No newline at end of file
+}
+
+/// Forwards to a scheduler shared between the runs of a Fiber, see
+/// `Options::scheduler`
+#[derive(Debug)]
+struct SharedScheduler(Arc<pulse::Scheduler + Send + Sync>);
+
+impl pulse::Scheduler for SharedScheduler {
+    fn wait(&self, signal: Signal) -> Result<(), pulse::WaitError> {
+        // The wait may outlive this forwarder, which is only installed until the
+        // Fiber yields
+        let sched = self.0.clone();
+        sched.wait(signal)
+    }
+
+    fn wait_timeout_ms(&self, signal: Signal, ms: u32) -> Result<(), pulse::TimeoutError> {
+        let sched = self.0.clone();
+        sched.wait_timeout_ms(signal, ms)
+    }
+}
+
+lazy_static! {
+    // Fibers blocked in `Resume::wait`, by the id of the signal they wait on, in the
+    // order they started waiting
+    static ref WAITERS: Mutex<HashMap<usize, Vec<FiberId>>> = Mutex::new(HashMap::new());
+}
+
+/// Get the Fiber currently waiting on `signal`, if any
+///
+/// Only the waits going through the default `Resume` scheduler are known. If
+/// several Fibers wait on the same signal, this is the one that has waited longest.
+pub fn waiter_of(signal: &Signal) -> Option<FiberId> {
+    WAITERS.lock().unwrap().get(&signal.id()).and_then(|ids| ids.first().cloned())
+}
+
+/// Records the current Fiber as a waiter of a signal, until dropped
+struct Waiter(usize, FiberId);
+
+impl Waiter {
+    /// `None` outside of a Fiber, where there is nothing to record
+    fn register(signal: &Signal) -> Option<Waiter> {
+        let fiber = running_fiber();
+        if fiber.is_null() {
+            return None;
+        }
+        let id = unsafe { (*fiber).id };
+        WAITERS.lock().unwrap().entry(signal.id()).or_insert_with(Vec::new).push(id);
+        Some(Waiter(signal.id(), id))
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        let mut waiters = WAITERS.lock().unwrap();
+        let empty = match waiters.get_mut(&self.0) {
+            Some(ids) => {
+                if let Some(pos) = ids.iter().position(|id| *id == self.1) {
+                    ids.remove(pos);
+                }
+                ids.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            waiters.remove(&self.0);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test-support"))]
+mod test {
+    use std::sync::atomic::Ordering;
+
+    use fiber::{Fiber, running_fiber, check_parent_chain};
+
+    #[test]
+    fn test_check_parent_chain_corrupted() {
+        Fiber::spawn(move|| {
+            assert!(check_parent_chain().is_ok());
+
+            let fiber = unsafe { &*running_fiber() };
+            fiber.running.store(false, Ordering::Release);
+            assert!(check_parent_chain().is_err());
+            fiber.running.store(true, Ordering::Release);
+        }).run();
+    }
+}
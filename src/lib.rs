@@ -15,29 +15,38 @@
 #![allow(unused_features)]
 #![feature(std_misc, libc, asm, core, alloc, test, unboxed_closures, page_size)]
 #![feature(rustc_private)]
-#![feature(unique, box_raw)]
+#![feature(unique, box_raw, arc_weak)]
 #![feature(core_simd, rt)]
 
 #[macro_use] extern crate log;
+#[macro_use] extern crate lazy_static;
 extern crate libc;
 extern crate test;
 extern crate mmap;
 extern crate pulse;
+extern crate time;
 
 pub use builder::Builder;
-pub use fiber::{Fiber, Handle, ResumeResult};
-pub use stack::StackPool;
+pub use fiber::{Driver, Fiber, FiberId, FinishReason, Handle, SharedHandle, WeakHandle, ResumeResult, ResumeError, SpawnError, StateWatch, TimeBreakdown, TraceContext};
+pub use stack::{StackArena, StackPool};
 
 mod context;
 pub mod fiber;
 
 pub mod builder;
+mod barrier;
+mod timer;
 mod stack;
 mod thunk; // use self-maintained thunk, because std::thunk is temporary. May be replaced by FnBox in the future.
 mod sys;
 
+#[cfg(feature = "test-support")]
+pub mod test_support;
+
 #[cfg(test)]
 mod tests;
+#[cfg(test)]
+mod benchmarks;
 
 /// Spawn a new Fiber
 ///
@@ -47,3 +56,19 @@ pub fn spawn<F>(f: F) -> Handle
 {
     Builder::new().spawn(f)
 }
+
+/// Spawn a new Fiber, or return why it could not be spawned
+///
+/// Equavalent to `Fiber::try_spawn`.
+pub fn try_spawn<F>(f: F) -> Result<Handle, SpawnError>
+    where F: FnOnce() + Send + 'static
+{
+    Builder::new().try_spawn(f)
+}
+
+/// Get the Handle of the current Fiber
+///
+/// Equavalent to `Fiber::current`.
+pub fn current() -> &'static Handle {
+    Fiber::current()
+}
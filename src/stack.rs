@@ -9,18 +9,29 @@
 // except according to those terms.
 
 use std::ptr;
+use std::mem;
+use std::cmp;
 use std::env::{page_size};
 use std::fmt;
 use std::sync::{Mutex, Arc};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::collections::HashMap;
 
 use libc;
 
 use mmap::{MemoryMap, MapOption};
 
+use fiber::{self, SpawnError};
+use sys;
+
 /// A task's stack. The name "Stack" is a vestige of segmented stacks.
 pub struct Stack {
     buf: Option<MemoryMap>,
     min_size: usize,
+    // Bytes skipped at the low end of `buf` to align `start`
+    offset: usize,
+    // Inaccessible pages at `start`, at least one
+    guard_pages: usize,
     pool: Option<StackPool>
 }
 
@@ -31,7 +42,7 @@ impl fmt::Debug for Stack {
             Some(ref map) => try!(write!(f, "Some({:#x}), ", map.data() as libc::uintptr_t)),
             None => try!(write!(f, "None, ")),
         }
-        write!(f, "min_size: {:?} {}", self.min_size, "}")
+        write!(f, "min_size: {:?}, guard_pages: {:?} {}", self.min_size, self.guard_pages, "}")
     }
 }
 
@@ -54,55 +65,167 @@ impl Stack {
     /// Allocate a new stack of `size`. If size = 0, this will fail. Use
     /// `dummy_stack` if you want a zero-sized stack.
     pub fn new(size: usize) -> Stack {
-        // Map in a stack. Eventually we might be able to handle stack
-        // allocation failure, which would fail to spawn the task. But there's
-        // not many sensible things to do on OOM.  Failure seems fine (and is
-        // what the old stack allocation did).
-        let stack = match MemoryMap::new(size, &[MapOption::MapReadable,
-                                                 MapOption::MapWritable,
-                                                 MapOption::MapNonStandardFlags(STACK_FLAGS)]) {
-            Ok(map) => map,
-            Err(e) => panic!("mmap for stack of size {} failed: {}", size, e)
+        // Not many sensible things to do on OOM. Failure seems fine (and is
+        // what the old stack allocation did); see `try_new` otherwise.
+        match Stack::try_new(size) {
+            Ok(stack) => stack,
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    /// Allocate a new stack of `size`, failing instead of panicking if it can't be
+    /// mapped.
+    pub fn try_new(size: usize) -> Result<Stack, SpawnError> {
+        Stack::try_with_guard(size, 1)
+    }
+
+    /// Allocate a new stack of `size` with `guard_pages` guard pages instead of one,
+    /// see `try_with_guard`
+    pub fn with_guard(size: usize, guard_pages: usize) -> Stack {
+        match Stack::try_with_guard(size, guard_pages) {
+            Ok(stack) => stack,
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    /// Allocate a new stack of `size` whose low end is `guard_pages` inaccessible
+    /// pages, failing instead of panicking if it can't be mapped
+    ///
+    /// Frames that reserve more than a page at once can jump over a single guard
+    /// page; more guard pages catch larger ones. `size` counts one guard page like
+    /// for `new`, the others are mapped on top of it. 0 is taken as 1.
+    pub fn try_with_guard(size: usize, guard_pages: usize) -> Result<Stack, SpawnError> {
+        Stack::try_with_guard_at(size, guard_pages, ptr::null()).map(|stack| stack.unwrap())
+    }
+
+    /// Allocate a stack like `try_with_guard`, asking for it to be mapped at `hint`
+    /// if it is not null. The stack may end up elsewhere.
+    /// `None` if `hint` is not null and the stack could not be placed there
+    fn try_with_guard_at(size: usize, guard_pages: usize, hint: *const u8)
+        -> Result<Option<Stack>, SpawnError>
+    {
+        let guard_pages = cmp::max(guard_pages, 1);
+        let len = size + (guard_pages - 1) * page_size();
+        let stack = if hint.is_null() {
+            try!(map_stack(len))
+        } else {
+            match try!(map_stack_at(len, hint)) {
+                Some(stack) => stack,
+                None => return Ok(None),
+            }
         };
 
-        // Change the last page to be inaccessible. This is to provide safety;
+        // Change the last pages to be inaccessible. This is to provide safety;
         // when an FFI function overflows it will (hopefully) hit this guard
         // page. It isn't guaranteed, but that's why FFI is unsafe. buf.data is
         // guaranteed to be aligned properly.
-        if !protect_last_page(&stack) {
-            panic!("Could not memory-protect guard page. stack={:?}",
-                  stack.data());
+        if !protect_last_page(stack.data(), guard_pages * page_size()) {
+            return Err(SpawnError::GuardPage);
         }
 
-        Stack {
+        let stack = Stack {
             buf: Some(stack),
             min_size: size,
+            offset: 0,
+            guard_pages: guard_pages,
             pool: None
+        };
+        stack.set_canary();
+        stack.register_guard();
+        Ok(Some(stack))
+    }
+
+    /// Allocate a new stack of `size` whose `start` is aligned to `align`, which
+    /// must be a power of two. Alignments up to a page are what `new` already
+    /// gives.
+    ///
+    /// mmap only promises page alignment, so this maps `size + align` bytes and
+    /// skips the head up to the first aligned address. The skipped head is made
+    /// inaccessible together with the guard page, but it stays reserved: every
+    /// such stack costs up to `align` extra bytes of address space (none of it
+    /// committed memory). It can't be unmapped on its own, since the MemoryMap
+    /// unmaps its whole range on drop.
+    pub fn new_aligned(size: usize, align: usize) -> Stack {
+        match Stack::try_new_aligned(size, align) {
+            Ok(stack) => stack,
+            Err(e) => panic!("{}", e)
         }
     }
 
+    /// Fallible version of `new_aligned`
+    pub fn try_new_aligned(size: usize, align: usize) -> Result<Stack, SpawnError> {
+        assert!(align.is_power_of_two(), "stack alignment {} is not a power of two", align);
+        if align <= page_size() {
+            return Stack::try_new(size);
+        }
+
+        let stack = try!(map_stack(size + align));
+        let data = stack.data() as usize;
+        let offset = (align - (data & (align - 1))) & (align - 1);
+
+        // The guard page now sits at the aligned start, with the unused head
+        // below it
+        if !protect_last_page(stack.data(), offset + page_size()) {
+            return Err(SpawnError::GuardPage);
+        }
+
+        let stack = Stack {
+            buf: Some(stack),
+            min_size: size,
+            offset: offset,
+            guard_pages: 1,
+            pool: None
+        };
+        stack.set_canary();
+        stack.register_guard();
+        Ok(stack)
+    }
+
     /// Create a 0-length stack which starts (and ends) at 0.
     #[allow(dead_code)]
     pub unsafe fn dummy_stack() -> Stack {
         Stack {
             buf: None,
             min_size: 0,
+            offset: 0,
+            guard_pages: 1,
             pool: None
         }
     }
 
     #[allow(dead_code)]
     pub fn guard(&self) -> *const usize {
-        (self.start() as usize + page_size()) as *const usize
+        (self.start() as usize + self.guard_pages * page_size()) as *const usize
     }
 
     /// Point to the low end of the allocated stack
     pub fn start(&self) -> *const usize {
         self.buf.as_ref()
-            .map(|m| m.data() as *const usize)
+            .map(|m| unsafe { m.data().offset(self.offset as isize) as *const usize })
             .unwrap_or(ptr::null())
     }
 
+    /// Tell the stack overflow handler about the guard pages, if it is installed
+    fn register_guard(&self) {
+        if sys::overflow::installed() {
+            sys::overflow::register(self.start() as usize, self.guard() as usize);
+        }
+    }
+
+    /// Take the mapping out of the stack, to unmap it
+    fn release(&mut self) -> Option<MemoryMap> {
+        if self.buf.is_some() && sys::overflow::installed() {
+            sys::overflow::unregister(self.start() as usize);
+        }
+        self.buf.take()
+    }
+
+    /// Number of bytes mapped for the stack, guard page and alignment head included.
+    /// 0 for the dummy stack.
+    pub fn mapped_len(&self) -> usize {
+        self.buf.as_ref().map(|buf| buf.len()).unwrap_or(0)
+    }
+
     /// Point one usize beyond the high end of the allocated stack
     pub fn end(&self) -> *const usize {
         self.buf
@@ -114,83 +237,746 @@ impl Stack {
     }
 }
 
+/// Byte the usable part of a stack is filled with by `fill_watermark`
+const WATERMARK: u8 = 0xa5;
+
+impl Stack {
+    /// Fill the stack above the guard page with a known pattern, for `high_water`
+    ///
+    /// This touches every page of the stack, committing the whole of it: it is meant
+    /// for sizing stacks during development. Must be done before a context is set up
+    /// on the stack, as it overwrites everything.
+    pub fn fill_watermark(&self) {
+        if self.buf.is_none() {
+            return;
+        }
+        let lo = self.guard() as usize;
+        unsafe {
+            ptr::write_bytes(lo as *mut u8, WATERMARK, self.end() as usize - lo);
+        }
+        self.set_canary();
+    }
+
+    /// Number of bytes of the stack that have been written to since
+    /// `fill_watermark`, counted from its end
+    ///
+    /// Frames are only found if they overwrote the pattern, so this may fall short
+    /// by the few bytes a frame reserves but never writes.
+    pub fn high_water(&self) -> usize {
+        if self.buf.is_none() {
+            return 0;
+        }
+        // The lowest word may hold the canary
+        let lo = self.guard() as usize + mem::size_of::<usize>();
+        let hi = self.end() as usize;
+        let mut addr = lo;
+        while addr < hi && unsafe { *(addr as *const u8) } == WATERMARK {
+            addr += 1;
+        }
+        hi - addr
+    }
+}
+
+/// Written to the lowest usable word of each stack, the last one a Fiber reaches
+/// before overflowing into the guard page
+#[cfg(feature = "test-support")]
+const CANARY: usize = 0x57ac_c0de;
+
+#[cfg(feature = "test-support")]
+impl Stack {
+    fn set_canary(&self) {
+        unsafe {
+            *(self.guard() as *mut usize) = CANARY;
+        }
+    }
+
+    /// Check that the guard page is still protected and the canary unbroken
+    pub fn check_intact(&self) -> Result<(), String> {
+        if self.buf.is_none() {
+            return Ok(());
+        }
+        for page in 0..self.guard_pages {
+            if is_readable((self.start() as usize + page * page_size()) as *const usize) {
+                return Err(format!("guard page {} of {:?} is readable", page, self));
+            }
+        }
+        let canary = unsafe { *self.guard() };
+        if canary != CANARY {
+            return Err(format!("canary of {:?} is broken: {:#x}", self, canary));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "test-support"))]
+impl Stack {
+    #[inline(always)]
+    fn set_canary(&self) {}
+}
+
 impl Drop for Stack {
     fn drop(&mut self) {
-        match (self.buf.take(), self.pool.clone().take()) {
-            (Some(s), Some(p)) => p.give_stack(Stack {
-                buf: Some(s),
-                min_size: self.min_size,
-                pool: self.pool.take()
-            }),
-            _ => ()
+        match self.pool.take() {
+            Some(p) => if let Some(s) = self.buf.take() {
+                p.give_stack(Stack {
+                    buf: Some(s),
+                    min_size: self.min_size,
+                    offset: self.offset,
+                    guard_pages: self.guard_pages,
+                    pool: None
+                })
+            },
+            None => if let Some(buf) = self.release() {
+                defer_unmap(buf)
+            },
         }
     }
 }
 
 unsafe impl Send for Stack {}
 
+/// Unpooled stack waiting to be unmapped, see `set_unmap_batch`
+struct Deferred(MemoryMap);
+
+unsafe impl Send for Deferred {}
+
+static UNMAP_BATCH: AtomicUsize = ATOMIC_USIZE_INIT;
+
+lazy_static! {
+    static ref DEFERRED: Mutex<Vec<Deferred>> = Mutex::new(Vec::new());
+}
+
+/// Defer unmapping the stacks that are not pooled until `batch` of them are
+/// dropped, then unmap them all at once. 0, the default, unmaps each one right away.
+///
+/// The stacks of a batch that are adjacent in memory, as the kernel tends to map
+/// them, are unmapped together by a single munmap. The address space of the
+/// deferred stacks stays in use meanwhile.
+pub fn set_unmap_batch(batch: usize) {
+    UNMAP_BATCH.store(batch, Ordering::Relaxed);
+    if batch == 0 {
+        flush_unmaps();
+    }
+}
+
+/// Unmap all the stacks whose unmapping was deferred
+pub fn flush_unmaps() {
+    let deferred = mem::replace(&mut *DEFERRED.lock().unwrap(), Vec::new());
+    unmap_batch(deferred);
+}
 
+/// Sort the `(start, len)` ranges, and merge those that touch
 #[cfg(unix)]
-fn protect_last_page(stack: &MemoryMap) -> bool {
+fn merge_ranges(mut ranges: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    ranges.sort();
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(ranges.len());
+    for (start, len) in ranges {
+        let touches = merged.last().map_or(false, |&(s, l)| s + l == start);
+        if touches {
+            merged.last_mut().unwrap().1 += len;
+        } else {
+            merged.push((start, len));
+        }
+    }
+    merged
+}
+
+/// Unmap the stacks of a batch, merging the ranges that touch
+#[cfg(unix)]
+fn unmap_batch(batch: Vec<Deferred>) {
+    let ranges: Vec<(usize, usize)> = batch.into_iter().map(|Deferred(buf)| {
+        let range = (buf.data() as usize, buf.len());
+        // Unmapped below, as part of its merged range
+        mem::forget(buf);
+        range
+    }).collect();
+    for (start, len) in merge_ranges(ranges) {
+        unsafe {
+            libc::munmap(start as *mut libc::c_void, len as libc::size_t);
+        }
+    }
+}
+
+/// Unmap the stacks of a batch one by one, mappings can't be released together here
+#[cfg(windows)]
+fn unmap_batch(batch: Vec<Deferred>) {
+    drop(batch);
+}
+
+fn defer_unmap(buf: MemoryMap) {
+    let batch = UNMAP_BATCH.load(Ordering::Relaxed);
+    if batch == 0 {
+        return;
+    }
+    let full = {
+        let mut deferred = DEFERRED.lock().unwrap();
+        deferred.push(Deferred(buf));
+        if deferred.len() >= batch {
+            mem::replace(&mut *deferred, Vec::new())
+        } else {
+            Vec::new()
+        }
+    };
+    // Unmapped without holding the lock
+    unmap_batch(full);
+}
+
+fn map_stack(size: usize) -> Result<MemoryMap, SpawnError> {
+    MemoryMap::new(size, &[MapOption::MapReadable,
+                           MapOption::MapWritable,
+                           MapOption::MapNonStandardFlags(STACK_FLAGS)])
+        .map_err(|e| SpawnError::StackAllocation(size, e))
+}
+
+/// Map a stack at exactly `hint`, `None` if something is mapped there already
+///
+/// `MapOption::MapAddr` means `MAP_FIXED`, which would replace whatever is in the
+/// way, so the range is first claimed with `hint` as a plain hint. Only once the
+/// system has handed out that very range is it mapped over with `MAP_FIXED`.
+#[cfg(unix)]
+fn map_stack_at(size: usize, hint: *const u8) -> Result<Option<MemoryMap>, SpawnError> {
+    let len = (size + page_size() - 1) / page_size() * page_size();
+    let claimed = unsafe {
+        libc::mmap(hint as *mut libc::c_void, len as libc::size_t,
+                   libc::PROT_NONE, STACK_FLAGS, -1, 0)
+    };
+    if claimed == libc::MAP_FAILED {
+        return Ok(None);
+    }
+    if claimed as *const u8 != hint {
+        unsafe { libc::munmap(claimed, len as libc::size_t); }
+        return Ok(None);
+    }
+    MemoryMap::new(size, &[MapOption::MapReadable,
+                           MapOption::MapWritable,
+                           MapOption::MapAddr(hint),
+                           MapOption::MapNonStandardFlags(STACK_FLAGS | libc::MAP_FIXED)])
+        .map(Some)
+        .map_err(|e| {
+            unsafe { libc::munmap(claimed, len as libc::size_t); }
+            SpawnError::StackAllocation(size, e)
+        })
+}
+
+/// Windows does not replace an existing mapping, the allocation fails instead
+#[cfg(windows)]
+fn map_stack_at(size: usize, hint: *const u8) -> Result<Option<MemoryMap>, SpawnError> {
+    Ok(MemoryMap::new(size, &[MapOption::MapReadable,
+                              MapOption::MapWritable,
+                              MapOption::MapAddr(hint),
+                              MapOption::MapNonStandardFlags(STACK_FLAGS)]).ok())
+}
+
+#[cfg(unix)]
+fn protect_last_page(start: *mut u8, len: usize) -> bool {
     unsafe {
         // This may seem backwards: the start of the segment is the last page?
         // Yes! The stack grows from higher addresses (the end of the allocated
         // block) to lower addresses (the start of the allocated block).
-        let last_page = stack.data() as *mut libc::c_void;
-        libc::mprotect(last_page, page_size() as libc::size_t,
+        let last_page = start as *mut libc::c_void;
+        libc::mprotect(last_page, len as libc::size_t,
                        libc::PROT_NONE) != -1
     }
 }
 
 #[cfg(windows)]
-fn protect_last_page(stack: &MemoryMap) -> bool {
+fn protect_last_page(start: *mut u8, len: usize) -> bool {
     unsafe {
         // see above
-        let last_page = stack.data() as *mut libc::c_void;
+        let last_page = start as *mut libc::c_void;
         let mut old_prot: libc::DWORD = 0;
-        libc::VirtualProtect(last_page, page_size() as libc::SIZE_T,
+        libc::VirtualProtect(last_page, len as libc::SIZE_T,
                              libc::PAGE_NOACCESS,
                              &mut old_prot as libc::LPDWORD) != 0
     }
 }
 
-#[derive(Debug)]
+// Writing from an unreadable address into a pipe fails with EFAULT instead
+// of faulting, which makes for a safe probe
+#[cfg(all(unix, any(test, feature = "test-support")))]
+fn is_readable(addr: *const usize) -> bool {
+    extern {
+        fn pipe(fds: *mut libc::c_int) -> libc::c_int;
+    }
+
+    unsafe {
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(pipe(fds.as_mut_ptr()), 0);
+        let ret = libc::write(fds[1], addr as *const libc::c_void, 1);
+        libc::close(fds[0]);
+        libc::close(fds[1]);
+        ret == 1
+    }
+}
+
 struct InnerPool {
-    // Ideally this would be some data structure that preserved ordering on
-    // Stack.min_size.
-    stacks: Vec<Stack>,    
+    // Sorted on Stack.min_size, so that the smallest stack that fits can be
+    // found with a binary search
+    stacks: Vec<Stack>,
+    // Most stacks kept cached, any more are unmapped
+    capacity: usize,
+    // Most stacks kept cached per size class, for the classes that have a limit
+    class_capacity: HashMap<usize, usize>,
+    on_evict: Option<Arc<Fn(usize) + Send + Sync>>,
+}
+
+impl fmt::Debug for InnerPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("InnerPool")
+            .field("stacks", &self.stacks)
+            .field("capacity", &self.capacity)
+            .field("class_capacity", &self.class_capacity)
+            .field("on_evict", &self.on_evict.is_some())
+            .finish()
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct StackPool(Arc<Mutex<InnerPool>>);
 
+thread_local!(static THREAD_POOL: StackPool = StackPool::new());
+
 impl StackPool {
     pub fn new() -> StackPool {
+        StackPool::with_capacity(256)
+    }
+
+    /// Create a pool keeping at most `capacity` stacks cached, see `set_capacity`
+    pub fn with_capacity(capacity: usize) -> StackPool {
         StackPool(Arc::new(Mutex::new(InnerPool{
             stacks: vec![],
+            capacity: capacity,
+            class_capacity: HashMap::new(),
+            on_evict: None,
         })))
     }
 
+    /// Set how many stacks the pool keeps cached, 256 by default. Stacks given back
+    /// beyond that are unmapped.
+    pub fn set_capacity(&self, capacity: usize) {
+        let evicted = {
+            let mut pool = self.0.lock().unwrap();
+            pool.capacity = capacity;
+            let mut evicted = Vec::new();
+            while pool.stacks.len() > capacity {
+                evicted.push(pool.stacks.pop().unwrap());
+            }
+            (evicted, pool.on_evict.clone())
+        };
+        evict(evicted.0, evicted.1);
+    }
+
+    /// Limit how many stacks of the size class of `size` the pool keeps cached, on
+    /// top of the overall capacity
+    ///
+    /// Stacks are classed by their size rounded up to a power of two, so that with
+    /// `set_class_capacity(16 * 1024, n)` at most `n` stacks between 8KiB and 16KiB are
+    /// kept. Classes without a limit of their own are only bound by the overall
+    /// capacity.
+    pub fn set_class_capacity(&self, size: usize, capacity: usize) {
+        let class = size_class(size);
+        let evicted = {
+            let mut pool = self.0.lock().unwrap();
+            pool.class_capacity.insert(class, capacity);
+            let mut kept = 0;
+            let mut evicted = Vec::new();
+            let mut idx = 0;
+            while idx < pool.stacks.len() {
+                if size_class(pool.stacks[idx].min_size) == class {
+                    kept += 1;
+                    if kept > capacity {
+                        evicted.push(pool.stacks.remove(idx));
+                        continue;
+                    }
+                }
+                idx += 1;
+            }
+            (evicted, pool.on_evict.clone())
+        };
+        evict(evicted.0, evicted.1);
+    }
+
+    /// Register a callback called with the mapped size of each stack the pool
+    /// unmaps instead of keeping it, replacing any previous one
+    ///
+    /// The callback is called after the stack was unmapped, without holding the
+    /// pool's lock.
+    pub fn on_evict<F>(&self, cb: F)
+        where F: Fn(usize) + Send + Sync + 'static
+    {
+        self.0.lock().unwrap().on_evict = Some(Arc::new(cb));
+    }
+
+    /// The pool that Fibers spawned on the current thread take their stacks from,
+    /// unless they opted out of pooling
+    pub fn thread_default() -> StackPool {
+        THREAD_POOL.with(|pool| pool.clone())
+    }
+
+    /// Allocate stacks up front, `count` of each `size` of `spec`, so that the first
+    /// Fibers of those sizes find them in the pool
+    ///
+    /// The stacks are given to the pool like any other, so the capacities still
+    /// apply. Stops at the first stack that can't be allocated.
+    pub fn prewarm_classes(&self, spec: &[(usize, usize)]) -> Result<(), SpawnError> {
+        for &(size, count) in spec.iter() {
+            for _ in 0..count {
+                self.give_stack(try!(Stack::try_new(size)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmap all the stacks cached in the pool, telling `on_evict` about each
+    pub fn clear(&self) {
+        let evicted = {
+            let mut pool = self.0.lock().unwrap();
+            (mem::replace(&mut pool.stacks, Vec::new()), pool.on_evict.clone())
+        };
+        evict(evicted.0, evicted.1);
+    }
+
+    /// Number of stacks currently cached in the pool
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().stacks.len()
+    }
+
+    /// Move all the stacks cached in this pool to `other`
+    ///
+    /// The stacks are given to `other` like returning stacks are, so any beyond its
+    /// capacities are evicted and reported to its `on_evict`. Cached stacks belong to
+    /// no pool until they are taken again, from `other` then.
+    pub fn drain_into(&self, other: &StackPool) {
+        let stacks = {
+            let mut pool = self.0.lock().unwrap();
+            mem::replace(&mut pool.stacks, Vec::new())
+        };
+        for stack in stacks.into_iter() {
+            other.give_stack(stack);
+        }
+    }
+
     pub fn take_stack(self, min_size: usize) -> Stack {
-        let mut stack = {
+        match self.try_take_stack(min_size) {
+            Ok(stack) => stack,
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    /// Take a stack of at least `min_size` from the pool, mapping a new one if none
+    /// fits. Fails if the new stack can't be mapped.
+    pub fn try_take_stack(self, min_size: usize) -> Result<Stack, SpawnError> {
+        self.try_take_stack_with_guard(min_size, 1)
+    }
+
+    /// Take a stack with `guard_pages` guard pages from the pool, or allocate one,
+    /// see `Stack::try_with_guard`
+    pub fn try_take_stack_with_guard(self, min_size: usize, guard_pages: usize)
+        -> Result<Stack, SpawnError>
+    {
+        if fiber::in_signal_context() {
+            return Err(SpawnError::InSignalHandler);
+        }
+        let guard_pages = cmp::max(guard_pages, 1);
+        let cached = {
             let mut pool = self.0.lock().unwrap();
 
-            // Ideally this would be a binary search
-            pool.stacks.iter()
-                .position(|s| min_size <= s.min_size)
-                .map(|idx| pool.stacks.swap_remove(idx))
-        }.unwrap_or_else(|| Stack::new(min_size));
+            // The smallest stack that fits
+            let start = sorted_position(&pool.stacks, min_size);
+            match pool.stacks[start..].iter().position(|s| s.guard_pages == guard_pages) {
+                Some(idx) => Some(pool.stacks.remove(start + idx)),
+                None => None,
+            }
+        };
+        let mut stack = match cached {
+            Some(stack) => stack,
+            None => try!(Stack::try_with_guard(min_size, guard_pages)),
+        };
 
         stack.pool = Some(self);
-        stack
+        Ok(stack)
     }
 
     pub fn give_stack(&self, mut stack: Stack) {
-        let mut pool = self.0.lock().unwrap();
-        stack.pool = None;
+        let on_evict = {
+            let mut pool = self.0.lock().unwrap();
+            stack.pool = None;
+
+            let class = size_class(stack.min_size);
+            let class_full = match pool.class_capacity.get(&class) {
+                Some(&capacity) => {
+                    pool.stacks.iter().filter(|s| size_class(s.min_size) == class).count() >= capacity
+                }
+                None => false,
+            };
+            if pool.stacks.len() < pool.capacity && !class_full {
+                let idx = sorted_position(&pool.stacks, stack.min_size);
+                pool.stacks.insert(idx, stack);
+                return;
+            }
+            pool.on_evict.clone()
+        };
+        evict(vec![stack], on_evict);
+    }
+}
+
+/// Index of the first of the sorted `stacks` at least `min_size` large
+fn sorted_position(stacks: &[Stack], min_size: usize) -> usize {
+    match stacks.binary_search_by(|s| s.min_size.cmp(&min_size)) {
+        Ok(idx) | Err(idx) => idx,
+    }
+}
+
+/// Range of addresses that stacks are placed in one after the other, so that the
+/// Nth stack taken from it lands at the same address from one run to the next
+///
+/// This is meant for comparing crash dumps across runs. Nothing is reserved up
+/// front, and nothing already mapped is ever replaced: a slot whose address is taken,
+/// a stack larger than a slot, and every stack once the slots are used up, are
+/// mapped wherever the system wants instead. Slots are never reused, and arena
+/// stacks are not pooled.
+#[derive(Debug)]
+pub struct StackArena {
+    base: usize,
+    slot_size: usize,
+    slots: usize,
+    next: AtomicUsize,
+}
+
+impl StackArena {
+    /// Create an arena of `slots` slots of `slot_size` bytes from `base`, both rounded
+    /// up to whole pages
+    pub fn new(base: usize, slot_size: usize, slots: usize) -> StackArena {
+        let page = page_size();
+        StackArena {
+            base: (base + page - 1) / page * page,
+            slot_size: (slot_size + page - 1) / page * page,
+            slots: slots,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Allocate a stack in the next slot of the arena, see `Stack::try_with_guard`
+    pub fn try_take_stack(&self, min_size: usize, guard_pages: usize) -> Result<Stack, SpawnError> {
+        let len = min_size + (cmp::max(guard_pages, 1) - 1) * page_size();
+        if len <= self.slot_size {
+            let slot = self.next.fetch_add(1, Ordering::Relaxed);
+            if slot < self.slots {
+                let hint = self.base + slot * self.slot_size;
+                if let Some(stack) = try!(Stack::try_with_guard_at(min_size, guard_pages,
+                                                                   hint as *const u8)) {
+                    return Ok(stack);
+                }
+            }
+        }
+        Stack::try_with_guard(min_size, guard_pages)
+    }
+}
+
+fn size_class(size: usize) -> usize {
+    size.next_power_of_two()
+}
+
+/// Unmap `stacks`, then tell `on_evict` about each of them
+fn evict(stacks: Vec<Stack>, on_evict: Option<Arc<Fn(usize) + Send + Sync>>) {
+    for mut stack in stacks.into_iter() {
+        let size = stack.release().map(|buf| buf.len()).unwrap_or(0);
+        if let Some(ref on_evict) = on_evict {
+            on_evict(size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env::page_size;
+
+    use libc;
+
+    use fiber::SpawnError;
+    use stack::{Stack, StackArena, StackPool, is_readable};
+    #[cfg(unix)]
+    use stack::merge_ranges;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_new_aligned() {
+        let align = 64 * 1024;
+        let stack = Stack::new_aligned(128 * 1024, align);
+
+        assert_eq!(stack.start() as usize % align, 0);
+        assert!(stack.end() as usize - stack.start() as usize >= 128 * 1024);
+
+        // The guard page is still in place at the low end
+        assert!(!is_readable(stack.start()));
+        assert!(!is_readable((stack.start() as usize + page_size() - 1) as *const usize));
+        assert!(is_readable((stack.start() as usize + page_size()) as *const usize));
+    }
 
-        if pool.stacks.len() < 256 {
-            pool.stacks.push(stack);
+    #[test]
+    fn test_stack_allocation_failure() {
+        let size = !0 >> 1;
+        match Stack::try_new(size) {
+            Err(SpawnError::StackAllocation(s, _)) => assert_eq!(s, size),
+            other => panic!("unexpected {:?}", other),
         }
+
+        let pool = StackPool::new();
+        match pool.clone().try_take_stack(size) {
+            Err(SpawnError::StackAllocation(s, _)) => assert_eq!(s, size),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn test_pool_on_evict() {
+        use std::sync::{Arc, Mutex};
+
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let pool = StackPool::new();
+        {
+            let evicted = evicted.clone();
+            pool.on_evict(move|size| evicted.lock().unwrap().push(size));
+        }
+        pool.set_capacity(2);
+
+        let stacks: Vec<Stack> = (0..4).map(|i| pool.clone().take_stack((i + 1) * 64 * 1024)).collect();
+        drop(stacks);
+        assert_eq!(pool.len(), 2);
+        assert_eq!(*evicted.lock().unwrap(), vec![3 * 64 * 1024, 4 * 64 * 1024]);
+
+        pool.set_capacity(1);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(evicted.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_pool_with_capacity() {
+        let pool = StackPool::with_capacity(4);
+
+        let stacks: Vec<Stack> = (0..8).map(|_| pool.clone().take_stack(64 * 1024)).collect();
+        assert_eq!(pool.len(), 0);
+        drop(stacks);
+        assert_eq!(pool.len(), 4);
+
+        pool.clear();
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_guard_pages() {
+        let page = page_size();
+        let stack = Stack::with_guard(64 * 1024, 3);
+
+        assert_eq!(stack.guard() as usize, stack.start() as usize + 3 * page);
+        for i in 0..3 {
+            assert!(!is_readable((stack.start() as usize + i * page) as *const usize));
+        }
+        assert!(is_readable(stack.guard()));
+        // As much usable stack as with one guard page
+        assert_eq!(stack.end() as usize - stack.guard() as usize, 64 * 1024 - page);
+
+        // Only reused for stacks with as many guard pages
+        let pool = StackPool::new();
+        pool.give_stack(stack);
+        assert_eq!(pool.clone().take_stack(64 * 1024).guard_pages, 1);
+        assert_eq!(pool.clone().try_take_stack_with_guard(64 * 1024, 3).unwrap().guard_pages, 3);
+    }
+
+    #[test]
+    fn test_pool_prewarm_classes() {
+        let pool = StackPool::new();
+        pool.prewarm_classes(&[(64 * 1024, 2), (256 * 1024, 3)]).unwrap();
+        assert_eq!(pool.len(), 5);
+
+        // Taken from the pool rather than mapped
+        let large = pool.clone().take_stack(256 * 1024);
+        assert_eq!((large.min_size, pool.len()), (256 * 1024, 4));
+        let small = pool.clone().take_stack(64 * 1024);
+        assert_eq!((small.min_size, pool.len()), (64 * 1024, 3));
+    }
+
+    #[test]
+    fn test_pool_best_fit() {
+        let pool = StackPool::new();
+        for &size in [1024 * 1024, 64 * 1024, 256 * 1024].iter() {
+            pool.give_stack(Stack::new(size));
+        }
+
+        let stack = pool.clone().take_stack(128 * 1024);
+        assert_eq!(stack.min_size, 256 * 1024);
+        let stack = pool.clone().take_stack(1);
+        assert_eq!(stack.min_size, 64 * 1024);
+        let stack = pool.clone().take_stack(128 * 1024);
+        assert_eq!(stack.min_size, 1024 * 1024);
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn test_pool_class_capacity() {
+        let pool = StackPool::new();
+        pool.set_class_capacity(16 * 1024, 2);
+        pool.set_class_capacity(256 * 1024, 1);
+
+        let small: Vec<Stack> = (0..4).map(|_| pool.clone().take_stack(16 * 1024)).collect();
+        let large: Vec<Stack> = (0..4).map(|_| pool.clone().take_stack(256 * 1024)).collect();
+        let medium: Vec<Stack> = (0..4).map(|_| pool.clone().take_stack(64 * 1024)).collect();
+        drop(small);
+        drop(large);
+        assert_eq!(pool.len(), 3);
+
+        // Another class without a limit of its own is unaffected
+        drop(medium);
+        assert_eq!(pool.len(), 7);
+
+        pool.set_class_capacity(64 * 1024, 1);
+        assert_eq!(pool.len(), 4);
+    }
+
+    #[test]
+    fn test_pool_drain_into() {
+        let a = StackPool::new();
+        let b = StackPool::new();
+        b.set_capacity(3);
+
+        let stacks: Vec<Stack> = (0..5).map(|_| a.clone().take_stack(64 * 1024)).collect();
+        drop(stacks);
+        assert_eq!(a.len(), 5);
+
+        a.drain_into(&b);
+        assert_eq!(a.len(), 0);
+        assert_eq!(b.len(), 3);
+
+        // Taken from b, it goes back to b
+        drop(b.clone().take_stack(64 * 1024));
+        assert_eq!((a.len(), b.len()), (0, 3));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_merge_ranges() {
+        let merged = merge_ranges(vec![(0x5000, 0x1000), (0x1000, 0x2000),
+                                       (0x3000, 0x1000), (0x8000, 0x1000)]);
+        assert_eq!(merged, vec![(0x1000, 0x3000), (0x5000, 0x1000), (0x8000, 0x1000)]);
+        assert!(merge_ranges(Vec::new()).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_arena_skips_taken_slot() {
+        let page = page_size();
+        let slot_size = 64 * 1024;
+        // Whatever the system gives us, so that the first slot is known to be taken
+        let blocker = Stack::new(slot_size);
+        let base = blocker.start() as usize;
+        unsafe { *((base + page) as *mut usize) = 0x5ba9; }
+
+        let arena = StackArena::new(base, slot_size, 2);
+        let stack = arena.try_take_stack(slot_size - page, 1).unwrap();
+        assert!(stack.start() as usize != base);
+        assert_eq!(unsafe { *((base + page) as *const usize) }, 0x5ba9);
+        assert!(!is_readable(base as *const usize));
     }
 }
@@ -226,3 +226,226 @@ pub mod stack {
         }
     }
 }
+
+/// Reporting of Fiber stack overflows, from a SIGSEGV/SIGBUS handler.
+///
+/// The guard pages of the live stacks are kept in a fixed table, as the handler
+/// can neither allocate nor block. Each entry is a single word, the page aligned
+/// start of the range with its number of pages in the low bits, so the table is
+/// read and written with plain atomics and the handler never waits on a fault
+/// that arrives in the middle of an update.
+#[allow(dead_code, non_camel_case_types)]
+pub mod overflow {
+    use std::mem;
+    use std::env::page_size;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+
+    /// Most guard ranges tracked, the stacks beyond that are not reported
+    const MAX_GUARDS: usize = 4096;
+
+    /// Bits of an entry holding the number of pages, below the smallest page size
+    const PAGES_MASK: usize = 0xfff;
+
+    static mut GUARDS: [usize; MAX_GUARDS] = [0; MAX_GUARDS];
+    static PAGE_SIZE: AtomicUsize = ATOMIC_USIZE_INIT;
+    static INSTALLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+    #[no_stack_check]
+    fn slot(idx: usize) -> &'static AtomicUsize {
+        unsafe { mem::transmute(&GUARDS[idx]) }
+    }
+
+    /// Remember `[lo, hi)` as a guard range
+    ///
+    /// Ranges that are not page aligned, or too long to be packed in an entry, are
+    /// not reported.
+    pub fn register(lo: usize, hi: usize) {
+        let page = page_size();
+        PAGE_SIZE.store(page, Ordering::Relaxed);
+        let pages = (hi - lo) / page;
+        if lo & (page - 1) != 0 || pages == 0 || pages > PAGES_MASK {
+            return;
+        }
+        let entry = lo | pages;
+        for idx in 0..MAX_GUARDS {
+            if slot(idx).compare_and_swap(0, entry, Ordering::Release) == 0 {
+                return;
+            }
+        }
+    }
+
+    /// Forget the guard range starting at `lo`
+    pub fn unregister(lo: usize) {
+        for idx in 0..MAX_GUARDS {
+            let entry = slot(idx).load(Ordering::Relaxed);
+            if entry != 0 && entry & !PAGES_MASK == lo {
+                slot(idx).store(0, Ordering::Release);
+                return;
+            }
+        }
+    }
+
+    /// Whether `addr` lies in a known guard range
+    #[no_stack_check]
+    fn in_guard(addr: usize) -> bool {
+        let page = PAGE_SIZE.load(Ordering::Relaxed);
+        let mut idx = 0;
+        while idx < MAX_GUARDS {
+            let entry = slot(idx).load(Ordering::Acquire);
+            let lo = entry & !PAGES_MASK;
+            if entry != 0 && lo <= addr && addr < lo + (entry & PAGES_MASK) * page {
+                return true;
+            }
+            idx += 1;
+        }
+        false
+    }
+
+    /// Whether the handler was installed by `install`
+    pub fn installed() -> bool {
+        INSTALLED.load(Ordering::Relaxed)
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    mod imp {
+        use std::mem;
+        use std::ptr;
+        use std::cell::Cell;
+        use std::sync::atomic::Ordering;
+        use libc::{self, c_int, c_void, size_t};
+        use mmap::{MemoryMap, MapOption};
+
+        use super::{in_guard, INSTALLED};
+
+        const SIGBUS: c_int = 7;
+        const SIGSEGV: c_int = 11;
+        const SA_SIGINFO: c_int = 4;
+        const SA_ONSTACK: c_int = 0x08000000;
+        const SS_DISABLE: c_int = 2;
+        const SIGSTKSZ: usize = 64 * 1024;
+        const SIG_DFL: usize = 0;
+        const SIG_IGN: usize = 1;
+
+        #[repr(C)]
+        struct sigaction {
+            sa_sigaction: usize,
+            sa_mask: [u64; 16],
+            sa_flags: c_int,
+            sa_restorer: usize,
+        }
+
+        #[repr(C)]
+        struct siginfo_t {
+            si_signo: c_int,
+            si_errno: c_int,
+            si_code: c_int,
+            _pad: c_int,
+            si_addr: usize,
+        }
+
+        #[repr(C)]
+        struct stack_t {
+            ss_sp: *mut c_void,
+            ss_flags: c_int,
+            ss_size: size_t,
+        }
+
+        extern {
+            #[link_name = "sigaction"]
+            fn sigaction_(signum: c_int, act: *const sigaction, oldact: *mut sigaction) -> c_int;
+            fn sigaltstack(ss: *const stack_t, oss: *mut stack_t) -> c_int;
+            fn raise(sig: c_int) -> c_int;
+        }
+
+        static mut OLD_SEGV: sigaction = sigaction { sa_sigaction: 0, sa_mask: [0; 16], sa_flags: 0, sa_restorer: 0 };
+        static mut OLD_BUS: sigaction = sigaction { sa_sigaction: 0, sa_mask: [0; 16], sa_flags: 0, sa_restorer: 0 };
+
+        thread_local!(static HAS_ALTSTACK: Cell<bool> = Cell::new(false));
+
+        #[no_stack_check]
+        extern "C" fn handler(signum: c_int, info: *mut siginfo_t, ctx: *mut c_void) {
+            let addr = unsafe { (*info).si_addr };
+            if in_guard(addr) {
+                const MSG: &'static [u8] = b"fiber stack overflow\n";
+                unsafe {
+                    libc::write(2, MSG.as_ptr() as *const c_void, MSG.len() as size_t);
+                    libc::abort();
+                }
+            }
+            // Not ours: hand it to the previous action, and stay installed
+            unsafe {
+                let old = if signum == SIGSEGV { &OLD_SEGV } else { &OLD_BUS };
+                match old.sa_sigaction {
+                    SIG_DFL => {
+                        // The default action kills the process, the signal is blocked
+                        // until this returns and then delivered again
+                        sigaction_(signum, old, ptr::null_mut());
+                        raise(signum);
+                    }
+                    SIG_IGN => {}
+                    f if old.sa_flags & SA_SIGINFO != 0 => {
+                        let f: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) = mem::transmute(f);
+                        f(signum, info, ctx);
+                    }
+                    f => {
+                        let f: extern "C" fn(c_int) = mem::transmute(f);
+                        f(signum);
+                    }
+                }
+            }
+        }
+
+        pub fn install() -> bool {
+            // Installing twice would make the handler its own previous action
+            if INSTALLED.load(Ordering::Relaxed) {
+                return true;
+            }
+            unsafe {
+                let mut action: sigaction = mem::zeroed();
+                action.sa_sigaction = handler as usize;
+                action.sa_flags = SA_SIGINFO | SA_ONSTACK;
+                if sigaction_(SIGSEGV, &action, &mut OLD_SEGV) != 0 ||
+                   sigaction_(SIGBUS, &action, &mut OLD_BUS) != 0 {
+                    return false;
+                }
+            }
+            INSTALLED.store(true, Ordering::Relaxed);
+            ensure_altstack();
+            true
+        }
+
+        /// Give the current thread a signal stack, if it has none yet: the handler
+        /// can't run on the stack that overflowed
+        pub fn ensure_altstack() {
+            if HAS_ALTSTACK.with(|has| has.get()) {
+                return;
+            }
+            unsafe {
+                let mut old: stack_t = mem::zeroed();
+                sigaltstack(ptr::null(), &mut old);
+                if old.ss_flags & SS_DISABLE != 0 {
+                    let map = match MemoryMap::new(SIGSTKSZ, &[MapOption::MapReadable,
+                                                               MapOption::MapWritable]) {
+                        Ok(map) => map,
+                        Err(_) => return,
+                    };
+                    let stack = stack_t { ss_sp: map.data() as *mut c_void, ss_flags: 0, ss_size: SIGSTKSZ as size_t };
+                    if sigaltstack(&stack, ptr::null_mut()) != 0 {
+                        return;
+                    }
+                    // In use for as long as the thread lives
+                    mem::forget(map);
+                }
+            }
+            HAS_ALTSTACK.with(|has| has.set(true));
+        }
+    }
+
+    #[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+    mod imp {
+        pub fn install() -> bool { false }
+        pub fn ensure_altstack() {}
+    }
+
+    pub use self::imp::{install, ensure_altstack};
+}
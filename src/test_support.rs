@@ -0,0 +1,81 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Assertions on the internal invariants of Fibers, for the tests of crates
+//! building on bran
+//!
+//! Only available with the `test-support` feature, which also writes a canary at
+//! the bottom of every stack.
+
+use fiber::{self, Handle};
+
+/// Assert that the stack of `fiber` is still sound: its guard page is protected,
+/// and the canary in its lowest word is unbroken
+///
+/// A broken canary means the Fiber has used up its whole stack, or something
+/// wrote past it.
+pub fn assert_stack_intact(fiber: &Handle) {
+    if let Err(e) = fiber.check_stack_intact() {
+        panic!("stack of Fiber {:?} is not intact: {}", fiber.id(), e);
+    }
+}
+
+/// Assert that the Fibers running on the current thread, nested in one another,
+/// are all consistently marked as running
+pub fn assert_parent_chain_consistent() {
+    if let Err(e) = fiber::check_parent_chain() {
+        panic!("inconsistent parent chain: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fiber::Fiber;
+    use test_support::{assert_stack_intact, assert_parent_chain_consistent};
+
+    #[test]
+    fn test_stack_intact() {
+        let fiber = Fiber::spawn(move|| {});
+        assert_stack_intact(&fiber);
+        assert!(fiber.run().is_finished());
+        assert_stack_intact(&fiber);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_stack_canary_broken() {
+        let fiber = Fiber::spawn(move|| {});
+        unsafe {
+            *(fiber.stack_guard() as *mut usize) = 0;
+        }
+        assert_stack_intact(&fiber);
+    }
+
+    #[test]
+    fn test_parent_chain_consistent() {
+        assert_parent_chain_consistent();
+        Fiber::spawn(move|| {
+            assert_parent_chain_consistent();
+            Fiber::spawn(move|| assert_parent_chain_consistent()).run();
+            assert_parent_chain_consistent();
+        }).run();
+    }
+}
@@ -19,10 +19,55 @@
 // IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
 // CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex, Once, ONCE_INIT};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::collections::VecDeque;
+use std::env;
+use std::process::Command;
 
-use fiber::Fiber;
-use pulse::Signal;
+use log::{self, Log, LogRecord, LogMetadata, LogLevelFilter};
+
+use fiber::{self, Driver, Handle, Fiber, Barrier, FinishReason, ResumeError};
+use builder::Builder;
+use stack::StackPool;
+use pulse::{self, Scheduler, Signal};
+
+lazy_static! {
+    // Messages captured by `CaptureLogger`
+    static ref CAPTURED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    // Held by the tests that depend on where panic messages go
+    static ref PANIC_OUTPUT: Mutex<()> = Mutex::new(());
+}
+
+struct CaptureLogger;
+
+impl Log for CaptureLogger {
+    fn enabled(&self, _: &LogMetadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &LogRecord) {
+        CAPTURED.lock().unwrap().push(format!("{}", record.args()));
+    }
+}
+
+/// Install `CaptureLogger` as the logger of the test binary, for warnings and errors
+fn capture_logs() {
+    static INIT: Once = ONCE_INIT;
+    INIT.call_once(|| {
+        log::set_logger(|max_level| {
+            max_level.set(LogLevelFilter::Warn);
+            Box::new(CaptureLogger)
+        }).unwrap();
+    });
+}
+
+fn captured(pattern: &str) -> bool {
+    CAPTURED.lock().unwrap().iter().any(|msg| msg.contains(pattern))
+}
 
 #[test]
 fn test_fiber_basic() {
@@ -34,6 +79,29 @@ fn test_fiber_basic() {
     assert_eq!(rx.recv().unwrap(), 1);
 }
 
+#[test]
+fn test_fiber_float_registers() {
+    // Floating point values live across the swaps on both sides, in the
+    // callee-saved FP registers where the architecture has them
+    let (tx, rx) = channel();
+    let fiber = Fiber::spawn(move|| {
+        let mut acc = 1.5f64;
+        for i in 0..8 {
+            acc = acc * 1.25 + i as f64;
+            Fiber::sched();
+        }
+        tx.send(acc).unwrap();
+    });
+
+    let mut acc = 1.5f64;
+    for i in 0..8 {
+        acc = acc * 1.25 + i as f64;
+        assert!(fiber.run().is_pending());
+    }
+    assert!(fiber.run().is_finished());
+    assert_eq!(rx.recv().unwrap(), acc);
+}
+
 #[test]
 fn test_fiber_yield() {
     let (s0, p0) = Signal::new();
@@ -75,3 +143,1558 @@ fn test_fiber_run_after_finished() {
     assert!(fiber.run().is_finished());
 }
 
+#[test]
+fn test_fiber_barrier() {
+    let barrier = Arc::new(Barrier::new(4));
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let (tx, rx) = channel();
+
+    let fibers: Vec<_> = (0..4).map(|_| {
+        let barrier = barrier.clone();
+        let log = log.clone();
+        let tx = tx.clone();
+        Fiber::spawn(move|| {
+            for phase in 0..2 {
+                log.lock().unwrap().push(phase);
+                if barrier.wait() {
+                    tx.send(phase).unwrap();
+                }
+            }
+        })
+    }).collect();
+
+    while fibers.iter().any(|f| f.state().is_pending()) {
+        for fiber in fibers.iter() {
+            fiber.run();
+        }
+    }
+
+    assert!(fibers.iter().all(|f| f.state().is_finished()));
+    assert_eq!(*log.lock().unwrap(), [0, 0, 0, 0, 1, 1, 1, 1]);
+    assert_eq!(barrier.phase(), 2);
+
+    // Exactly one leader per phase
+    drop(tx);
+    assert_eq!(rx.iter().collect::<Vec<_>>(), [0, 1]);
+}
+
+#[test]
+fn test_fiber_reset() {
+    let (tx, rx) = channel();
+    let mut fiber = Fiber::spawn(move|| {});
+    assert!(fiber.run().is_finished());
+
+    for i in 0..3 {
+        let tx = tx.clone();
+        fiber.reset(move|| {
+            tx.send(i).unwrap();
+        });
+        assert!(fiber.run().is_finished());
+        assert_eq!(rx.recv().unwrap(), i);
+    }
+}
+
+#[test]
+fn test_fiber_resume_busy() {
+    let (started_tx, started_rx) = channel();
+    let (done_tx, done_rx) = channel();
+
+    let fiber = Fiber::spawn(move|| {
+        started_tx.send(()).unwrap();
+        // Keep running until the other thread tried to resume us
+        done_rx.recv().unwrap();
+    }).into_shared();
+
+    let other = fiber.clone();
+    let racer = thread::spawn(move|| {
+        started_rx.recv().unwrap();
+        let res = other.resume();
+        done_tx.send(()).unwrap();
+        res
+    });
+
+    assert!(fiber.resume().unwrap().is_finished());
+    assert_eq!(racer.join().unwrap().unwrap_err(), ResumeError::Busy);
+}
+
+#[test]
+fn test_fiber_weak_handle() {
+    let fiber = Fiber::spawn(move|| Fiber::sched()).into_shared();
+    let other = fiber.clone();
+    let weak = fiber.downgrade();
+
+    // Usable while the Fiber is alive
+    assert!(weak.upgrade().unwrap().run().is_pending());
+    drop(fiber);
+    assert!(weak.upgrade().unwrap().run().is_finished());
+
+    drop(other);
+    assert!(weak.upgrade().is_none());
+    assert!(weak.clone().upgrade().is_none());
+}
+
+#[test]
+fn test_fiber_is_root() {
+    let (tx, rx) = channel();
+
+    Fiber::spawn(move|| {
+        let inner_tx = tx.clone();
+        Fiber::spawn(move|| {
+            inner_tx.send(fiber::is_root()).unwrap();
+        }).run();
+
+        // Back from the child, we must still know our own parent
+        tx.send(fiber::is_root()).unwrap();
+    }).run();
+
+    assert!(!fiber::is_root());
+    assert_eq!(rx.recv().unwrap(), false);
+    assert_eq!(rx.recv().unwrap(), true);
+}
+
+#[test]
+fn test_fiber_no_spurious_resume() {
+    let (s0, p0) = Signal::new();
+    let (s1, p1) = Signal::new();
+    let resumed = Arc::new(AtomicUsize::new(0));
+
+    let counter = resumed.clone();
+    let fiber = Fiber::spawn(move|| {
+        s0.wait().unwrap();
+        counter.fetch_add(1, Ordering::SeqCst);
+        s1.wait().unwrap();
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+
+    assert!(fiber.run().is_pending());
+    p0.pulse();
+    assert!(fiber.run().is_pending());
+    assert_eq!(resumed.load(Ordering::SeqCst), 1);
+
+    // The pulsed signal is gone, the Fiber now waits on the new one
+    assert!(fiber.run().is_pending());
+    assert!(fiber.run().is_pending());
+    assert_eq!(resumed.load(Ordering::SeqCst), 1);
+
+    p1.pulse();
+    assert!(fiber.run().is_finished());
+    assert_eq!(resumed.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_fiber_reset_after_panic() {
+    let mut fiber = Fiber::spawn(move|| {
+        panic!("Panic inside a fiber!!");
+    });
+    assert!(fiber.run().is_panic());
+
+    let (tx, rx) = channel();
+    fiber.reset(move|| {
+        tx.send(1).unwrap();
+    });
+    assert!(fiber.run().is_finished());
+    assert_eq!(rx.recv().unwrap(), 1);
+}
+
+#[test]
+fn test_fiber_waiter_of() {
+    let (signal, pulse) = Signal::new();
+    let watched = signal.clone();
+
+    let fiber = Fiber::spawn(move|| {
+        signal.wait().unwrap();
+    });
+    assert_eq!(fiber::waiter_of(&watched), None);
+
+    assert!(fiber.run().is_pending());
+    assert_eq!(fiber::waiter_of(&watched), Some(fiber.id()));
+
+    pulse.pulse();
+    assert!(fiber.run().is_finished());
+    assert_eq!(fiber::waiter_of(&watched), None);
+}
+
+#[test]
+fn test_fiber_waiter_of_two_waiters() {
+    let (signal, pulse) = Signal::new();
+    let watched = signal.clone();
+
+    let (first_signal, second_signal) = (signal.clone(), signal);
+    let first = Fiber::spawn(move|| {
+        first_signal.wait().unwrap();
+    });
+    let second = Fiber::spawn(move|| {
+        second_signal.wait().unwrap();
+    });
+
+    assert!(first.run().is_pending());
+    assert!(second.run().is_pending());
+    assert_eq!(fiber::waiter_of(&watched), Some(first.id()));
+
+    // The first waiter leaving does not take the second one's entry along
+    pulse.pulse();
+    assert!(first.run().is_finished());
+    assert_eq!(fiber::waiter_of(&watched), Some(second.id()));
+
+    assert!(second.run().is_finished());
+    assert_eq!(fiber::waiter_of(&watched), None);
+}
+
+#[test]
+fn test_fiber_panic_logged() {
+    let _guard = PANIC_OUTPUT.lock().unwrap();
+    capture_logs();
+
+    let fiber = Fiber::spawn(move|| {
+        panic!("Logged panic inside a fiber");
+    });
+    assert!(fiber.run().is_panic());
+    assert!(captured("Fiber panicked at 'Logged panic inside a fiber'"));
+}
+
+#[test]
+fn test_fiber_panic_hook() {
+    let _guard = PANIC_OUTPUT.lock().unwrap();
+    capture_logs();
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    {
+        let seen = seen.clone();
+        fiber::set_panic_hook(move|payload| {
+            let msg = payload.downcast_ref::<&str>().map(|s| s.to_string());
+            seen.lock().unwrap().push((Fiber::current().id(), msg));
+        });
+    }
+
+    let fiber = Fiber::spawn(move|| panic!("hooked panic"));
+    assert!(fiber.run().is_panic());
+    assert!(fiber::take_panic_hook().is_some());
+
+    // The hook is process-wide, the Fibers of tests running meanwhile panic into it too
+    let seen: Vec<_> = seen.lock().unwrap().iter()
+        .filter(|&&(id, _)| id == fiber.id())
+        .map(|&(_, ref msg)| msg.clone())
+        .collect();
+    assert_eq!(seen, vec![Some("hooked panic".to_string())]);
+    assert!(!captured("hooked panic"));
+    // The payload is still kept
+    assert_eq!(*fiber.take_panic().unwrap().downcast_ref::<&str>().unwrap(), "hooked panic");
+}
+
+#[test]
+fn test_fiber_panic_hook_reentrant() {
+    let _guard = PANIC_OUTPUT.lock().unwrap();
+
+    // A hook that panics itself, and one that panics another Fiber, neither of which
+    // may take the process or the hook down with them
+    fiber::set_panic_hook(move|_| panic!("panicking hook"));
+    let fiber = Fiber::spawn(move|| panic!("first panic"));
+    assert!(fiber.run().is_panic());
+
+    fiber::set_panic_hook(move|payload| {
+        if payload.downcast_ref::<&str>() == Some(&"outer panic") {
+            assert!(Fiber::spawn(move|| panic!("inner panic")).run().is_panic());
+        }
+    });
+    let fiber = Fiber::spawn(move|| panic!("outer panic"));
+    assert!(fiber.run().is_panic());
+    assert!(fiber::take_panic_hook().is_some());
+}
+
+#[test]
+fn test_fiber_unpooled_stack() {
+    // Every test runs on its own thread, so its pool starts empty
+    let pool = StackPool::thread_default();
+    assert_eq!(pool.len(), 0);
+
+    let fiber = Builder::new().pool_stack(false).spawn(move|| {});
+    assert!(fiber.run().is_finished());
+    drop(fiber);
+    assert_eq!(pool.len(), 0);
+
+    let fiber = Builder::new().spawn(move|| {});
+    assert!(fiber.run().is_finished());
+    drop(fiber);
+    assert_eq!(pool.len(), 1);
+}
+
+#[test]
+fn test_fiber_on_finish() {
+    let (tx, rx) = channel();
+
+    let finished_tx = tx.clone();
+    let fiber = Builder::new().on_finish(move|reason| finished_tx.send(reason).unwrap())
+                              .spawn(move|| {});
+    assert!(fiber.run().is_finished());
+    assert_eq!(rx.recv().unwrap(), FinishReason::Finished);
+
+    let panicked_tx = tx.clone();
+    let fiber = Builder::new().on_finish(move|reason| panicked_tx.send(reason).unwrap())
+                              .spawn(move|| panic!("Panic inside a fiber!!"));
+    assert!(fiber.run().is_panic());
+    assert_eq!(rx.recv().unwrap(), FinishReason::Panicked);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_fiber_swap_under_signals() {
+    use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+    use libc::{c_int, c_ulong};
+
+    const SIGUSR1: c_int = 10;
+
+    extern {
+        fn signal(signum: c_int, handler: usize) -> usize;
+        fn pthread_self() -> c_ulong;
+        fn pthread_kill(thread: c_ulong, sig: c_int) -> c_int;
+    }
+
+    static HANDLED: AtomicUsize = ATOMIC_USIZE_INIT;
+    static DONE: AtomicBool = ATOMIC_BOOL_INIT;
+
+    #[no_stack_check]
+    extern "C" fn handler(_: c_int) {
+        HANDLED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let old = unsafe { signal(SIGUSR1, handler as usize) };
+    let target = unsafe { pthread_self() };
+    let killer = thread::spawn(move|| {
+        while !DONE.load(Ordering::Relaxed) {
+            unsafe { pthread_kill(target, SIGUSR1); }
+        }
+    });
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let mut fiber = fiber::spawn_noop();
+    fiber.run();
+    for i in 0..100000 {
+        let counter = counter.clone();
+        fiber.reset(move|| {
+            // Every register the fiber relies on has to survive the swaps
+            let (a, b, c) = (i, i * 2, i * 3);
+            counter.fetch_add(a + b + c, Ordering::SeqCst);
+        });
+        assert!(fiber.run().is_finished());
+    }
+
+    DONE.store(true, Ordering::Relaxed);
+    killer.join().unwrap();
+    unsafe { signal(SIGUSR1, old); }
+
+    assert_eq!(counter.load(Ordering::SeqCst), (0..100000).map(|i| i * 6).fold(0, |a, b| a + b));
+    assert!(HANDLED.load(Ordering::Relaxed) > 0);
+}
+
+#[test]
+fn test_fiber_on_state_change() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let (s0, p0) = Signal::new();
+
+    let fiber = Fiber::spawn(move|| {
+        s0.wait().unwrap();
+    });
+    for tag in ["first", "second"].iter() {
+        let (seen, tag) = (seen.clone(), *tag);
+        fiber.on_state_change(move|state| {
+            let name = match *state {
+                fiber::State::Running => "running",
+                fiber::State::Pending(_) | fiber::State::PendingTimeout(_, _) => "pending",
+                fiber::State::Finished => "finished",
+                fiber::State::Panicked => "panicked",
+            };
+            seen.lock().unwrap().push(format!("{} {}", tag, name));
+        });
+    }
+
+    assert!(fiber.run().is_pending());
+    p0.pulse();
+    assert!(fiber.run().is_finished());
+
+    let expected: Vec<String> = ["running", "pending", "running", "finished"].iter()
+        .flat_map(|name| vec![format!("first {}", name), format!("second {}", name)].into_iter())
+        .collect();
+    assert_eq!(*seen.lock().unwrap(), expected);
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn test_fiber_deref_while_running() {
+    // Unblocks the fiber even when the racer panics
+    struct Notify(Sender<()>);
+
+    impl Drop for Notify {
+        fn drop(&mut self) {
+            let _ = self.0.send(());
+        }
+    }
+
+    let (started_tx, started_rx) = channel();
+    let (done_tx, done_rx) = channel();
+
+    let fiber = Fiber::spawn(move|| {
+        started_tx.send(()).unwrap();
+        let _ = done_rx.recv();
+    }).into_shared();
+
+    let racer = {
+        let fiber = fiber.clone();
+        thread::spawn(move|| {
+            started_rx.recv().unwrap();
+            let _notify = Notify(done_tx);
+            fiber.name();
+        })
+    };
+
+    assert!(fiber.run().is_finished());
+    assert!(racer.join().is_err());
+}
+
+#[test]
+fn test_fiber_try_spawn() {
+    let (tx, rx) = channel();
+    let fiber = Builder::new().try_spawn(move|| tx.send(1).unwrap()).unwrap();
+    assert!(fiber.run().is_finished());
+    assert_eq!(rx.recv().unwrap(), 1);
+
+    assert!(Fiber::try_spawn_with(move|| {}, StackPool::new()).unwrap().run().is_finished());
+}
+
+#[test]
+fn test_fiber_driver() {
+    struct RoundRobin {
+        ready: VecDeque<Handle>,
+    }
+
+    impl Driver for RoundRobin {}
+
+    impl RoundRobin {
+        fn run_all(&mut self) {
+            while let Some(fiber) = self.ready.pop_front() {
+                if self.drive(&fiber).is_pending() {
+                    self.ready.push_back(fiber);
+                }
+            }
+        }
+    }
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let barrier = Arc::new(Barrier::new(3));
+    let mut sched = RoundRobin { ready: VecDeque::new() };
+    for i in 0..3 {
+        let (log, barrier) = (log.clone(), barrier.clone());
+        sched.ready.push_back(Fiber::spawn(move|| {
+            log.lock().unwrap().push(("start", i));
+            barrier.wait();
+            log.lock().unwrap().push(("end", i));
+        }));
+    }
+    sched.run_all();
+
+    let log = log.lock().unwrap();
+    assert_eq!(log.len(), 6);
+    assert!(log[..3].iter().all(|&(what, _)| what == "start"));
+    assert!(log[3..].iter().all(|&(what, _)| what == "end"));
+}
+
+#[test]
+fn test_fiber_time_breakdown() {
+    let (s0, p0) = Signal::new();
+    let fiber = Fiber::spawn(move|| {
+        s0.wait().unwrap();
+    });
+
+    assert!(fiber.run().is_pending());
+    let before = fiber.time_breakdown();
+    thread::sleep_ms(100);
+    p0.pulse();
+    assert!(fiber.run().is_finished());
+
+    let after = fiber.time_breakdown();
+    let pending_ms = (after.pending_ns - before.pending_ns) / 1000000;
+    assert!(pending_ms >= 100 && pending_ms < 1000, "pending for {}ms", pending_ms);
+    assert!(after.running_ns < 100 * 1000000);
+}
+
+#[test]
+fn test_fiber_on_yield() {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let (s0, p0) = Signal::new();
+
+    let fiber = {
+        let counter = counter.clone();
+        Fiber::spawn(move|| {
+            fiber::on_yield(move|| { counter.fetch_add(1, Ordering::SeqCst); });
+            Fiber::sched();
+            Fiber::sched();
+            s0.wait().unwrap();
+        })
+    };
+
+    for expected in 1..3 {
+        assert!(fiber.run().is_pending());
+        assert_eq!(counter.load(Ordering::SeqCst), expected);
+    }
+    assert!(fiber.run().is_pending());
+    assert_eq!(counter.load(Ordering::SeqCst), 3);
+
+    p0.pulse();
+    assert!(fiber.run().is_finished());
+    assert_eq!(counter.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn test_fiber_resume_with_progress() {
+    let fiber = Fiber::spawn(move|| {
+        for percent in 1..4 {
+            fiber::report_progress(percent * 25);
+            Fiber::sched();
+        }
+        fiber::report_progress(100);
+    });
+
+    for expected in 1..4 {
+        let (state, progress) = fiber.resume_with_progress::<i32>().unwrap();
+        assert!(state.is_pending());
+        assert_eq!(progress, Some(expected * 25));
+    }
+    let (state, progress) = fiber.resume_with_progress::<i32>().unwrap();
+    assert!(state.is_finished());
+    assert_eq!(progress, Some(100));
+
+    assert_eq!(fiber.resume_with_progress::<i32>().unwrap().1, None);
+}
+
+#[test]
+fn test_fiber_captures_dropped_before_stack_returned() {
+    struct Guard {
+        pool: StackPool,
+        tx: Sender<(bool, usize)>,
+    }
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            // Still on the fiber, whose stack is still taken from the pool
+            self.tx.send((fiber::is_root(), self.pool.len())).unwrap();
+        }
+    }
+
+    let (tx, rx) = channel();
+    let pool = StackPool::new();
+    let guard = Guard { pool: pool.clone(), tx: tx };
+    let fiber = Fiber::spawn_with(move|| {
+        let _guard = guard;
+    }, pool.clone());
+
+    assert!(fiber.run().is_finished());
+    assert_eq!(rx.recv().unwrap(), (true, 0));
+
+    drop(fiber);
+    assert_eq!(pool.len(), 1);
+}
+
+#[test]
+fn test_fiber_context_during_unwind() {
+    struct Guard;
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            // Runs while the fiber unwinds, which must still be the current one
+            fiber::report_progress(fiber::is_root());
+        }
+    }
+
+    let fiber = Builder::new().name("unwinding".to_string()).spawn(move|| {
+        let _guard = Guard;
+        panic!("Panic inside a fiber!!");
+    });
+
+    let (state, progress) = fiber.resume_with_progress::<bool>().unwrap();
+    assert!(state.is_panic());
+    assert_eq!(progress, Some(true));
+    assert_eq!(fiber.name(), Some("unwinding"));
+}
+
+#[test]
+fn test_fiber_migrate_between_threads() {
+    const FIBERS: usize = 64;
+    const YIELDS: usize = 100;
+    const THREADS: usize = 4;
+
+    let total = Arc::new(AtomicUsize::new(0));
+    let queue = Arc::new(Mutex::new(VecDeque::new()));
+    for _ in 0..FIBERS {
+        let total = total.clone();
+        let fiber = Fiber::spawn(move|| {
+            // Lives on the fiber's stack, across every migration
+            let mut local = [0usize; 16];
+            for i in 0..YIELDS {
+                local[i % 16] += 1;
+                Fiber::sched();
+            }
+            total.fetch_add(local.iter().fold(0, |a, b| a + *b), Ordering::SeqCst);
+        }).into_shared();
+
+        // Two entries per fiber, so that threads race to resume the same one
+        let mut queue = queue.lock().unwrap();
+        queue.push_back(fiber.clone());
+        queue.push_back(fiber);
+    }
+
+    let workers: Vec<_> = (0..THREADS).map(|_| {
+        let queue = queue.clone();
+        thread::spawn(move|| {
+            loop {
+                let fiber = match queue.lock().unwrap().pop_front() {
+                    Some(fiber) => fiber,
+                    None => break,
+                };
+                match fiber.resume() {
+                    Ok(ref state) if state.is_finished() => (),
+                    Ok(ref state) => {
+                        assert!(state.is_pending());
+                        queue.lock().unwrap().push_back(fiber);
+                    }
+                    Err(ResumeError::Busy) => queue.lock().unwrap().push_back(fiber),
+                }
+            }
+        })
+    }).collect();
+
+    for worker in workers.into_iter() {
+        worker.join().unwrap();
+    }
+    assert_eq!(total.load(Ordering::SeqCst), FIBERS * YIELDS);
+}
+
+#[test]
+fn test_fiber_subscribe() {
+    let fiber = Fiber::spawn(move|| Fiber::sched());
+    let mut watch = fiber.subscribe();
+    assert!(watch.try_changed().is_none());
+
+    assert!(fiber.run().is_pending());
+    assert!(watch.changed().unwrap().is_running());
+    assert!(watch.changed().unwrap().is_pending());
+    assert!(watch.try_changed().is_none());
+
+    assert!(fiber.run().is_finished());
+    assert!(watch.changed().unwrap().is_running());
+    assert!(watch.changed().unwrap().is_finished());
+
+    drop(fiber);
+    assert!(watch.changed().is_none());
+}
+
+#[test]
+fn test_fiber_size_heuristic() {
+    let trivial = Builder::new().pool_stack(false).size_heuristic(true).spawn(move|| {});
+
+    let data = [1u8; 64 * 1024];
+    let large = Builder::new().pool_stack(false).size_heuristic(true).spawn(move|| {
+        assert_eq!(data.iter().fold(0usize, |a, b| a + *b as usize), 64 * 1024);
+    });
+
+    assert!(large.stack_size() >= trivial.stack_size() + 4 * 64 * 1024);
+    assert!(large.run().is_finished());
+    assert!(trivial.run().is_finished());
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn test_fiber_dropped_without_run() {
+    capture_logs();
+
+    let ran = Builder::new().name("ran".to_string()).spawn(move|| {});
+    let ran_id = ran.id();
+    assert_eq!(ran.resume_count(), 0);
+    assert!(ran.run().is_finished());
+    assert_eq!(ran.resume_count(), 1);
+    drop(ran);
+
+    let forgotten = Builder::new().name("forgotten".to_string()).spawn(move|| {});
+    let forgotten_id = forgotten.id();
+    drop(forgotten);
+
+    assert!(captured(&format!("Fiber Some(\"forgotten\") ({:?}) was dropped without ever being run",
+                             forgotten_id)));
+    assert!(!captured(&format!("({:?})", ran_id)));
+    assert!(!captured("Some(\"ran\")"));
+}
+
+#[test]
+fn test_fiber_stack_size() {
+    let fiber = Builder::new().pool_stack(false).stack_size(128 * 1024).spawn(move|| {});
+    assert_eq!(fiber.stack_size(), 128 * 1024);
+    assert!(fiber.run().is_finished());
+
+    // Rounded up to whole pages
+    let fiber = Builder::new().pool_stack(false).stack_size(128 * 1024 + 1).spawn(move|| {});
+    assert_eq!(fiber.stack_size(), 128 * 1024 + ::std::env::page_size());
+
+    // And never below the floor
+    let fiber = Builder::new().pool_stack(false).stack_size(1).spawn(move|| {});
+    assert_eq!(fiber.stack_size(), 16 * ::std::env::page_size());
+    assert!(fiber.run().is_finished());
+}
+
+#[test]
+fn test_fiber_initial_stack_pointer() {
+    // Spawning checks the initial stack pointer against the stack in debug builds
+    let page = ::std::env::page_size();
+    for &size in [1, 16 * page - 1, 16 * page, 16 * page + 1, 64 * 1024, 1024 * 1024].iter() {
+        for &colors in [0, 8, 1024].iter() {
+            let mut fiber = Builder::new()
+                .pool_stack(false)
+                .stack_size(size)
+                .stack_colors(colors)
+                .spawn(move|| Fiber::sched());
+            assert!(fiber.run().is_pending());
+            assert!(fiber.run().is_finished());
+
+            fiber.reset(move|| {});
+            assert!(fiber.run().is_finished());
+        }
+    }
+}
+
+#[test]
+fn test_fiber_stack_high_water() {
+    #[inline(never)]
+    fn descend(depth: usize) {
+        let pad = [depth; 64];
+        if depth > 0 {
+            descend(depth - 1);
+        }
+        ::test::black_box(&pad);
+    }
+
+    let spawn = |depth: usize| {
+        Builder::new()
+            .pool_stack(false)
+            .stack_size(1024 * 1024)
+            .stack_watermark(true)
+            .spawn(move|| descend(depth))
+    };
+
+    let shallow = spawn(0);
+    let deep = spawn(256);
+    assert!(shallow.run().is_finished());
+    assert!(deep.run().is_finished());
+
+    // At least 256 frames of 64 words each
+    assert!(shallow.stack_high_water() > 0);
+    let frames = 256 * 64 * ::std::mem::size_of::<usize>();
+    assert!(deep.stack_high_water() >= shallow.stack_high_water() + frames);
+    assert!(deep.stack_high_water() < deep.stack_size());
+
+    // Not measured without the watermark
+    let fiber = Builder::new().pool_stack(false).spawn(move|| descend(16));
+    assert!(fiber.run().is_finished());
+    assert_eq!(fiber.stack_high_water(), 0);
+}
+
+#[test]
+fn test_fiber_stack_mapped_bytes() {
+    let page = ::std::env::page_size();
+
+    // The guard page is mapped as part of the requested size
+    let fiber = Builder::new().pool_stack(false).stack_size(128 * 1024 + 1).spawn(move|| {});
+    assert_eq!(fiber.stack_mapped_bytes(), 128 * 1024 + page);
+    assert!(fiber.stack_mapped_bytes() >= fiber.stack_size());
+    assert!(fiber.run().is_finished());
+    assert_eq!(fiber.stack_mapped_bytes(), 128 * 1024 + page);
+}
+
+#[test]
+fn test_fiber_attach_resource() {
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let drops = Arc::new(AtomicUsize::new(0));
+    let fiber = Fiber::spawn(move|| {});
+    fiber.attach_resource(Counted(drops.clone()));
+
+    assert!(fiber.run().is_finished());
+    assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+    drop(fiber);
+    assert_eq!(drops.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_fiber_wait_timeout() {
+    use pulse::TimeoutError;
+
+    let (tx, rx) = channel();
+    let (s0, _p0) = Signal::new();
+    let fiber = Fiber::spawn(move|| {
+        let start = ::time::precise_time_ns();
+        let timed_out = match s0.wait_timeout_ms(50) {
+            Err(TimeoutError::Timeout) => true,
+            _ => false,
+        };
+        tx.send((timed_out, (::time::precise_time_ns() - start) / 1000000)).unwrap();
+    });
+
+    let mut state = fiber.run();
+    match state {
+        fiber::State::PendingTimeout(_, ms) => assert!(ms <= 50),
+        _ => panic!("unexpected {:?}", state),
+    }
+    while !state.is_finished() {
+        thread::sleep_ms(5);
+        state = fiber.run();
+    }
+
+    let (timed_out, elapsed_ms) = rx.recv().unwrap();
+    assert!(timed_out);
+    assert!(elapsed_ms >= 50);
+}
+
+#[test]
+fn test_resume_outside_fiber() {
+    use pulse::TimeoutError;
+
+    let (s0, _p0) = Signal::new();
+    match fiber::Resume.wait_timeout_ms(s0, 10) {
+        Err(TimeoutError::Timeout) => (),
+        res => panic!("unexpected {:?}", res),
+    }
+    assert!(fiber::Resume.wait(Signal::pulsed()).is_ok());
+}
+
+#[test]
+fn test_fiber_wait_timeout_dropped() {
+    use pulse::{TimeoutError, WaitError};
+
+    let (tx, rx) = channel();
+    let (s0, p0) = Signal::new();
+    let fiber = Fiber::spawn(move|| {
+        let dropped = match s0.wait_timeout_ms(10000) {
+            Err(TimeoutError::Error(WaitError::Dropped)) => true,
+            _ => false,
+        };
+        tx.send(dropped).unwrap();
+    });
+
+    match fiber.run() {
+        fiber::State::PendingTimeout(..) => {}
+        state => panic!("unexpected {:?}", state),
+    }
+    drop(p0);
+
+    assert!(fiber.run().is_finished());
+    assert!(rx.recv().unwrap());
+}
+
+#[test]
+fn test_fiber_resume_with() {
+    let (tx, rx) = channel();
+    let fiber = Fiber::spawn(move|| {
+        let mut sum = 0;
+        loop {
+            match Fiber::sched_value::<Option<i32>>() {
+                Some(n) => sum += n,
+                None => break,
+            }
+        }
+        tx.send(sum).unwrap();
+    });
+
+    assert!(fiber.run().is_pending());
+    for n in 1..5 {
+        assert!(fiber.resume_with(Some(n)).unwrap().is_pending());
+    }
+    assert!(fiber.resume_with(None::<i32>).unwrap().is_finished());
+    assert_eq!(rx.recv().unwrap(), 10);
+
+    // Mismatched types panic in the fiber
+    let fiber = Fiber::spawn(move|| { Fiber::sched_value::<i32>(); });
+    assert!(fiber.run().is_pending());
+    assert!(fiber.resume_with("not an i32").unwrap().is_panic());
+}
+
+#[test]
+fn test_fiber_sched_outside() {
+    // Left to the default in the end, for the other tests
+    fiber::set_sched_policy(fiber::SchedPolicy::Yield);
+    assert_eq!(fiber::sched_policy(), fiber::SchedPolicy::Yield);
+    Fiber::sched();
+    fiber::set_sched_policy(fiber::SchedPolicy::Ignore);
+    Fiber::sched();
+
+    // Inside of a fiber the policy does not matter
+    let fiber = Fiber::spawn(move|| Fiber::sched());
+    assert!(fiber.run().is_pending());
+    assert!(fiber.run().is_finished());
+}
+
+#[cfg(feature = "yield-history")]
+#[test]
+fn test_fiber_yield_history() {
+    #[inline(never)]
+    fn descend(depth: usize) {
+        let pad = [depth; 64];
+        Fiber::sched();
+        if depth > 0 {
+            descend(depth - 1);
+        }
+        ::test::black_box(&pad);
+    }
+
+    let fiber = Fiber::spawn(move|| descend(4));
+    while !fiber.run().is_finished() {}
+
+    let history = fiber.yield_history();
+    assert_eq!(history.len(), 5);
+    assert!(history.windows(2).all(|w| w[1] < w[0]), "{:?}", history);
+}
+
+#[test]
+fn test_fiber_yield_value() {
+    let fiber = Fiber::spawn(move|| {
+        for i in 0..5 {
+            Fiber::yield_value(i);
+        }
+    });
+
+    let mut values = Vec::new();
+    while let Some(val) = fiber.resume_yield::<i32>().unwrap() {
+        values.push(val);
+    }
+    assert_eq!(values, vec![0, 1, 2, 3, 4]);
+    assert!(fiber.state().is_finished());
+
+    let fiber = Fiber::spawn(move|| panic!("Panic inside a fiber!!"));
+    assert!(fiber.resume_yield::<i32>().is_err());
+}
+
+#[test]
+fn test_fiber_pulse_from_thread() {
+    for _ in 0..1000 {
+        let (s0, p0) = Signal::new();
+        let fiber = Fiber::spawn(move|| s0.wait().unwrap());
+
+        let pending = match fiber.run() {
+            fiber::State::Pending(sig) => sig,
+            state => panic!("unexpected {:?}", state),
+        };
+
+        // Races with the driver getting ready to block
+        let pulser = thread::spawn(move|| p0.pulse());
+        pending.wait().unwrap();
+        assert!(fiber.run().is_finished());
+        pulser.join().unwrap();
+    }
+}
+
+#[test]
+fn test_builder_name_prefix() {
+    let names: Vec<String> = (0..3)
+        .map(|_| Builder::new().name_prefix("worker").spawn(move|| {}))
+        .map(|fiber| {
+            assert!(fiber.run().is_finished());
+            fiber.name().unwrap().to_string()
+        })
+        .collect();
+    assert_eq!(names, vec!["worker-0", "worker-1", "worker-2"]);
+
+    // Counted per prefix, and overridden by a plain name
+    let fiber = Builder::new().name_prefix("other").spawn(move|| {});
+    assert_eq!(fiber.name(), Some("other-0"));
+    assert!(fiber.run().is_finished());
+    let fiber = Builder::new().name_prefix("worker").name("plain".to_string()).spawn(move|| {});
+    assert_eq!(fiber.name(), Some("plain"));
+    assert!(fiber.run().is_finished());
+}
+
+#[test]
+fn test_builder_stack_size() {
+    let page = ::std::env::page_size();
+
+    let fiber = Builder::new().pool_stack(false).stack_size(512 * 1024).spawn(move|| {});
+    assert_eq!(fiber.stack_size(), 512 * 1024);
+
+    let fiber = Builder::new().pool_stack(false).spawn(move|| {});
+    assert_eq!(fiber.stack_size(), 1024 * 1024);
+
+    let fiber = Builder::new().pool_stack(false).stack_size(0).spawn(move|| {});
+    assert_eq!(fiber.stack_size(), 16 * page);
+    assert!(fiber.run().is_finished());
+
+    // Fibers spawned from a pool get the default size too
+    let fiber = Fiber::spawn_with(move|| {}, StackPool::new());
+    assert!(fiber.stack_size() >= 1024 * 1024);
+}
+
+#[test]
+fn test_builder_name() {
+    let fiber = Builder::new().name("worker-1".to_string()).spawn(move|| {});
+    assert_eq!(fiber.name(), Some("worker-1"));
+    assert!(format!("{:?}", fiber).contains("worker-1"));
+    assert!(fiber.run().is_finished());
+}
+
+#[test]
+fn test_fiber_perform() {
+    struct AskNumber(&'static str);
+
+    let (tx, rx) = channel();
+    let fiber = Fiber::spawn(move|| {
+        let a: i32 = fiber::perform(AskNumber("a"));
+        let b: i32 = fiber::perform(AskNumber("b"));
+        let c: i32 = fiber::perform(AskNumber("c"));
+        tx.send(a + b + c).unwrap();
+    });
+
+    let mut asked = Vec::new();
+    let mut resp = None;
+    while let Some(AskNumber(what)) = fiber.resume_effect::<AskNumber, i32>(resp).unwrap() {
+        asked.push(what);
+        resp = Some(asked.len() as i32);
+    }
+
+    assert!(fiber.state().is_finished());
+    assert_eq!(asked, vec!["a", "b", "c"]);
+    assert_eq!(rx.recv().unwrap(), 1 + 2 + 3);
+}
+
+#[test]
+fn test_fiber_join() {
+    let (s0, p0) = Signal::new();
+    let fiber = Fiber::spawn(move|| {
+        Fiber::sched();
+        s0.wait().unwrap();
+    });
+    let pulser = thread::spawn(move|| {
+        thread::sleep_ms(10);
+        p0.pulse();
+    });
+    assert!(fiber.join().is_ok());
+    assert!(fiber.state().is_finished());
+    pulser.join().unwrap();
+
+    // Already finished
+    assert!(fiber.join().is_ok());
+
+    let fiber = Fiber::spawn(move|| panic!("Panic inside a fiber!!"));
+    assert!(fiber.join().is_err());
+    assert!(fiber.join().is_err());
+}
+
+#[test]
+fn test_fiber_join_panic_payload() {
+    let _output = PANIC_OUTPUT.lock().unwrap();
+    capture_logs();
+
+    let fiber = Builder::new().print_panic(false).spawn(move|| panic!("quiet payload"));
+    match fiber.join() {
+        Err(payload) => assert_eq!(*payload.downcast_ref::<&str>().unwrap(), "quiet payload"),
+        Ok(()) => panic!("the fiber did not panic"),
+    }
+    assert!(!captured("quiet payload"));
+
+    // Taken by the first join
+    assert!(fiber.take_panic().is_none());
+    assert!(fiber.join().is_err());
+}
+
+#[test]
+fn test_fiber_identity() {
+    assert!(fiber::identity().is_none());
+
+    let (tx, rx) = channel();
+    let fiber = Fiber::spawn(move|| {
+        for _ in 0..2 {
+            let name = thread::current().name().map(|s| s.to_string());
+            tx.send((fiber::identity(), name)).unwrap();
+            Fiber::sched();
+        }
+    });
+    let id = fiber.id();
+
+    let fiber = thread::Builder::new().name("first".to_string()).spawn(move|| {
+        assert!(fiber.run().is_pending());
+        fiber
+    }).unwrap().join().unwrap();
+    thread::Builder::new().name("second".to_string()).spawn(move|| {
+        assert!(fiber.run().is_pending());
+        assert!(fiber.run().is_finished());
+    }).unwrap().join().unwrap();
+
+    assert_eq!(rx.recv().unwrap(), (Some(id), Some("first".to_string())));
+    assert_eq!(rx.recv().unwrap(), (Some(id), Some("second".to_string())));
+}
+
+#[test]
+fn test_fiber_current() {
+    let (tx, rx) = channel();
+    let fiber = Builder::new().name("current".to_string()).spawn(move|| {
+        let outer = ::current().name().map(|s| s.to_string());
+        let inner = Builder::new().name("nested".to_string()).spawn(move|| {
+            assert_eq!(Fiber::current().name(), Some("nested"));
+        });
+        assert!(inner.run().is_finished());
+        let after = ::current().name().map(|s| s.to_string());
+        tx.send((outer, after)).unwrap();
+    });
+
+    assert!(fiber.run().is_finished());
+    let expected = Some("current".to_string());
+    assert_eq!(rx.recv().unwrap(), (expected.clone(), expected));
+}
+
+#[test]
+fn test_fiber_abort_on_panic() {
+    const CHILD: &'static str = "BRAN_ABORT_ON_PANIC_CHILD";
+
+    if env::var(CHILD).is_ok() {
+        let fiber = Builder::new().abort_on_panic(true).spawn(move|| panic!("fatal fiber"));
+        fiber.run();
+        unreachable!();
+    }
+
+    let output = Command::new(env::current_exe().unwrap())
+        .arg("test_fiber_abort_on_panic")
+        .env(CHILD, "1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(output.status.code().is_none());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("fatal fiber"));
+}
+
+#[derive(Debug)]
+struct CountingScheduler(Arc<AtomicUsize>);
+
+impl pulse::Scheduler for CountingScheduler {
+    fn wait(&self, signal: Signal) -> Result<(), pulse::WaitError> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        fiber::Resume.wait(signal)
+    }
+
+    fn wait_timeout_ms(&self, signal: Signal, ms: u32) -> Result<(), pulse::TimeoutError> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        fiber::Resume.wait_timeout_ms(signal, ms)
+    }
+}
+
+#[test]
+fn test_fiber_run_with() {
+    let waits = Arc::new(AtomicUsize::new(0));
+    let (s0, p0) = Signal::new();
+    let (s1, p1) = Signal::new();
+    let fiber = Fiber::spawn(move|| {
+        s0.wait().unwrap();
+        s1.wait().unwrap();
+    });
+
+    assert!(fiber.run_with(CountingScheduler(waits.clone())).is_pending());
+    assert_eq!(waits.load(Ordering::SeqCst), 1);
+    p0.pulse();
+    assert!(fiber.run_with(CountingScheduler(waits.clone())).is_pending());
+    assert_eq!(waits.load(Ordering::SeqCst), 2);
+    p1.pulse();
+    assert!(fiber.run().is_finished());
+    assert_eq!(waits.load(Ordering::SeqCst), 2);
+
+    // Installed by default with the Builder
+    let (s, p) = Signal::new();
+    let fiber = Builder::new()
+        .scheduler(CountingScheduler(waits.clone()))
+        .spawn(move|| s.wait().unwrap());
+    assert!(fiber.run().is_pending());
+    assert_eq!(waits.load(Ordering::SeqCst), 3);
+    p.pulse();
+    assert!(fiber.run().is_finished());
+}
+
+#[test]
+fn test_fiber_trace_context() {
+    assert!(fiber::trace_context().is_none());
+
+    let trace = ::TraceContext { trace_id: 0x7ace, span_id: 1 };
+    let (tx, rx) = channel();
+    let parent = Builder::new().trace_context(trace).spawn(move|| {
+        let child = Fiber::spawn(move|| {
+            let grandchild = Fiber::spawn(move|| {
+                tx.send(fiber::trace_context()).unwrap();
+            });
+            assert!(grandchild.run().is_finished());
+        });
+        assert!(child.run().is_finished());
+
+        // Overridden further down
+        let span = ::TraceContext { span_id: 2, .. fiber::trace_context().unwrap() };
+        let other = Builder::new().trace_context(span).spawn(move|| {
+            assert_eq!(fiber::trace_context(), Some(span));
+        });
+        assert!(other.run().is_finished());
+    });
+
+    assert!(parent.run().is_finished());
+    assert_eq!(rx.recv().unwrap(), Some(trace));
+
+    // Nothing to inherit outside of a Fiber
+    let fiber = Fiber::spawn(move|| assert!(fiber::trace_context().is_none()));
+    assert!(fiber.run().is_finished());
+}
+
+#[test]
+fn test_fiber_max_switch_rate() {
+    capture_logs();
+
+    let spin = |name: &str, coalesce: bool| {
+        let fiber = Builder::new()
+            .name(name.to_string())
+            .max_switch_rate(100, coalesce)
+            .spawn(move|| for _ in 0..10000 { Fiber::sched(); });
+        while !fiber.run().is_finished() {}
+        fiber
+    };
+
+    let fiber = spin("spinning", false);
+    assert!(captured("Some(\"spinning\")"));
+    assert_eq!(fiber.resume_count(), 10001);
+
+    // Only the first hundred yields of each second switch
+    let fiber = spin("coalesced", true);
+    assert!(captured("Some(\"coalesced\")"));
+    assert!(fiber.resume_count() < 1000, "{} switches", fiber.resume_count());
+}
+
+#[test]
+fn test_fiber_stack_arena() {
+    const CHILD: &'static str = "BRAN_STACK_ARENA_CHILD";
+    #[cfg(target_pointer_width = "64")]
+    const BASE: usize = 0x5ba0_0000_0000;
+    #[cfg(target_pointer_width = "32")]
+    const BASE: usize = 0x5ba0_0000;
+    const SLOT: usize = 1024 * 1024;
+
+    if env::var(CHILD).is_ok() {
+        let arena = Arc::new(::StackArena::new(BASE, SLOT, 4));
+        let fiber = Builder::new().stack_size(256 * 1024).stack_arena(arena).spawn(move|| {
+            use std::io::Write;
+            let local = 0u8;
+            let _ = writeln!(&mut ::std::io::stderr(), "stack at {:p}", &local);
+        });
+        assert!(fiber.run().is_finished());
+        return;
+    }
+
+    let run = || {
+        let output = Command::new(env::current_exe().unwrap())
+            .arg("test_fiber_stack_arena")
+            .env(CHILD, "1")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        let line = stderr.lines().find(|l| l.starts_with("stack at 0x")).unwrap().to_string();
+        usize::from_str_radix(&line["stack at 0x".len()..], 16).unwrap()
+    };
+
+    let first = run();
+    assert!(first >= BASE && first < BASE + SLOT, "{:#x}", first);
+    assert_eq!(run(), first);
+}
+
+#[test]
+fn test_fiber_with_local() {
+    let (tx, rx) = channel();
+    let counter = |start: usize| {
+        let tx = tx.clone();
+        Fiber::spawn(move|| {
+            fiber::with_local(|n: &mut usize| *n = start);
+            for _ in 0..3 {
+                Fiber::sched();
+                fiber::with_local(|n: &mut usize| *n += 1);
+            }
+            tx.send(fiber::with_local(|n: &mut usize| *n)).unwrap();
+        })
+    };
+
+    // Interleaved on this one thread
+    let a = counter(10);
+    let b = counter(20);
+    loop {
+        let a_done = a.run().is_finished();
+        let b_done = b.run().is_finished();
+        if a_done && b_done {
+            break;
+        }
+    }
+    assert_eq!(rx.recv().unwrap(), 13);
+    assert_eq!(rx.recv().unwrap(), 23);
+
+    // Another type, and another Fiber, start from the default
+    let fiber = Fiber::spawn(move|| {
+        assert_eq!(fiber::with_local(|s: &mut String| s.clone()), "");
+        assert_eq!(fiber::with_local(|n: &mut usize| *n), 0);
+    });
+    assert!(fiber.run().is_finished());
+}
+
+#[test]
+fn test_fiber_with_local_reset_and_panic() {
+    let (tx, rx) = channel();
+    let mut fiber = {
+        let tx = tx.clone();
+        Fiber::spawn(move|| {
+            fiber::with_local(|n: &mut usize| *n = 7);
+            // A panic in the closure leaves the value in place
+            let res = unsafe { ::std::rt::unwind::try(|| {
+                fiber::with_local(|n: &mut usize| { *n += 1; panic!("in with_local") });
+            }) };
+            assert!(res.is_err());
+            tx.send(fiber::with_local(|n: &mut usize| *n)).unwrap();
+        })
+    };
+    assert!(fiber.run().is_finished());
+    assert_eq!(rx.recv().unwrap(), 8);
+
+    // The next job of a reset Fiber starts from the default again
+    fiber.reset(move|| {
+        tx.send(fiber::with_local(|n: &mut usize| *n)).unwrap();
+    });
+    assert!(fiber.run().is_finished());
+    assert_eq!(rx.recv().unwrap(), 0);
+}
+
+struct DropSignal(Option<Sender<()>>);
+
+impl Drop for DropSignal {
+    fn drop(&mut self) {
+        self.0.take().unwrap().send(()).unwrap();
+    }
+}
+
+#[test]
+fn test_fiber_cancel() {
+    // Suspended on a signal that never comes
+    let (tx, rx) = channel();
+    let (finish_tx, finish_rx) = channel();
+    let (s, _p) = Signal::new();
+    let fiber = Builder::new()
+        .on_finish(move|reason| finish_tx.send(reason).unwrap())
+        .spawn(move|| {
+            let _guard = DropSignal(Some(tx));
+            s.wait().unwrap();
+            unreachable!();
+        });
+    assert!(fiber.run().is_pending());
+    assert!(rx.try_recv().is_err());
+
+    fiber.cancel().unwrap();
+    rx.recv().unwrap();
+    assert!(fiber.state().is_finished());
+    assert_eq!(finish_rx.recv().unwrap(), FinishReason::Cancelled);
+    assert!(fiber.take_panic().is_none());
+
+    // Cancelled before it ever ran: only the closure is dropped
+    let (tx, rx) = channel();
+    let guard = DropSignal(Some(tx));
+    let fiber = Fiber::spawn(move|| {
+        let _guard = guard;
+        unreachable!();
+    });
+    fiber.cancel().unwrap();
+    rx.recv().unwrap();
+    assert!(fiber.run().is_finished());
+}
+
+#[test]
+fn test_fiber_cancel_on_drop() {
+    let (tx, rx) = channel();
+    let fiber = Builder::new().cancel_on_drop(true).spawn(move|| {
+        let _guard = DropSignal(Some(tx));
+        loop {
+            Fiber::sched();
+        }
+    });
+    assert!(fiber.run().is_pending());
+    assert!(fiber.run().is_pending());
+    assert!(rx.try_recv().is_err());
+
+    drop(fiber);
+    rx.recv().unwrap();
+}
+
+#[test]
+fn test_fiber_cancel_waits_in_destructor() {
+    struct WaitOnDrop(Option<Signal>);
+
+    impl Drop for WaitOnDrop {
+        fn drop(&mut self) {
+            self.0.take().unwrap().wait().unwrap();
+        }
+    }
+
+    let (tx, rx) = channel();
+    let (s0, p0) = Signal::new();
+    let fiber = Fiber::spawn(move|| {
+        let _guard = DropSignal(Some(tx));
+        let _wait = WaitOnDrop(Some(s0));
+        loop {
+            Fiber::sched();
+        }
+    });
+    assert!(fiber.run().is_pending());
+
+    let pulser = thread::spawn(move|| {
+        thread::sleep_ms(10);
+        p0.pulse();
+    });
+    fiber.cancel().unwrap();
+    rx.recv().unwrap();
+    assert!(fiber.state().is_finished());
+    pulser.join().unwrap();
+}
+
+#[test]
+fn test_fiber_select() {
+    let (s0, _p0) = Signal::new();
+    let (s1, p1) = Signal::new();
+    let (s2, _p2) = Signal::new();
+    let (tx, rx) = channel();
+    let fiber = Fiber::spawn(move|| {
+        tx.send(fiber::select(&[s0, s1, s2])).unwrap();
+    });
+
+    assert!(fiber.run().is_pending());
+    assert!(rx.try_recv().is_err());
+    p1.pulse();
+    while !fiber.run().is_finished() {}
+    assert_eq!(rx.recv().unwrap(), 1);
+
+    // Already ready
+    let (s0, _p0) = Signal::new();
+    assert_eq!(fiber::select(&[s0, Signal::pulsed()]), 1);
+}
+
+#[test]
+fn test_fiber_sleep_ms() {
+    let (tx, rx) = channel();
+    let fiber = Fiber::spawn(move|| {
+        let start = ::time::precise_time_ns();
+        fiber::sleep_ms(50);
+        tx.send((::time::precise_time_ns() - start) / 1000000).unwrap();
+    });
+
+    // Block on what the Fiber waits for instead of polling it
+    let mut runs = 0;
+    loop {
+        runs += 1;
+        match fiber.run() {
+            fiber::State::PendingTimeout(signal, ms) => { let _ = signal.wait_timeout_ms(ms); }
+            fiber::State::Pending(signal) => { let _ = signal.wait(); }
+            state => { assert!(state.is_finished()); break; }
+        }
+    }
+
+    assert!(rx.recv().unwrap() >= 50);
+    assert!(runs <= 3, "{} runs", runs);
+}
+
+#[test]
+fn test_fiber_sleep_ms_pulsed() {
+    let (tx, rx) = channel();
+    let fiber = Fiber::spawn(move|| {
+        let start = ::time::precise_time_ns();
+        fiber::sleep_ms(50);
+        tx.send((::time::precise_time_ns() - start) / 1000000).unwrap();
+    });
+
+    // A driver that ignores the timeout, and only waits for the signal to come
+    loop {
+        match fiber.run() {
+            fiber::State::PendingTimeout(signal, _) | fiber::State::Pending(signal) => {
+                signal.wait().unwrap();
+            }
+            state => { assert!(state.is_finished()); break; }
+        }
+    }
+
+    assert!(rx.recv().unwrap() >= 50);
+}
+
+#[test]
+#[ignore]
+fn test_fiber_stack_overflow_reported() {
+    use std::sync::atomic::ATOMIC_USIZE_INIT;
+    use libc::c_int;
+
+    const CHILD: &'static str = "BRAN_STACK_OVERFLOW_CHILD";
+    const SIGSEGV: c_int = 11;
+
+    extern {
+        fn signal(signum: c_int, handler: usize) -> usize;
+        fn raise(sig: c_int) -> c_int;
+    }
+
+    static FOREIGN: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    #[no_stack_check]
+    extern "C" fn foreign(_: c_int) {
+        FOREIGN.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline(never)]
+    fn recurse(depth: usize) -> usize {
+        let pad = [depth; 64];
+        ::test::black_box(&pad);
+        recurse(depth + 1) + pad[0]
+    }
+
+    if env::var(CHILD).is_ok() {
+        // Faults that are not overflows go on to the previous handler, and the
+        // overflows after them are still reported
+        unsafe { signal(SIGSEGV, foreign as usize); }
+        assert!(fiber::report_stack_overflows());
+        for _ in 0..2 {
+            unsafe { raise(SIGSEGV); }
+        }
+        assert_eq!(FOREIGN.load(Ordering::Relaxed), 2);
+
+        let fiber = Builder::new().pool_stack(false).stack_size(64 * 1024).spawn(move|| {
+            recurse(0);
+        });
+        fiber.run();
+        unreachable!();
+    }
+
+    let output = Command::new(env::current_exe().unwrap())
+        .arg("test_fiber_stack_overflow_reported")
+        .arg("--ignored")
+        .env(CHILD, "1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("fiber stack overflow"));
+}
+
+#[test]
+fn test_fiber_spawn_in_signal_context() {
+    {
+        let _marker = fiber::enter_signal_context();
+        assert!(fiber::in_signal_context());
+        match Fiber::try_spawn(move|| {}) {
+            Err(::SpawnError::InSignalHandler) => (),
+            res => panic!("unexpected {:?}", res.map(|_| ())),
+        }
+        match StackPool::new().try_take_stack(64 * 1024) {
+            Err(::SpawnError::InSignalHandler) => (),
+            res => panic!("unexpected {:?}", res.map(|_| ())),
+        }
+    }
+
+    assert!(!fiber::in_signal_context());
+    assert!(Fiber::spawn(move|| {}).run().is_finished());
+}
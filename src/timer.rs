@@ -0,0 +1,101 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Rustcc developers
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+use pulse::{Pulse, Signal};
+use time;
+
+/// Pause the current Fiber for `ms` milliseconds
+///
+/// The Fiber waits on a signal that a background timer thread pulses once the time
+/// is up, through the installed scheduler: with `Resume` it is suspended
+/// `PendingTimeout`, so its driver can block on the signal until it is pulsed, or
+/// sleep out the timeout, instead of spinning. Outside of a Fiber, this blocks the
+/// thread.
+pub fn sleep_ms(ms: u32) {
+    let (signal, pulse) = Signal::new();
+    let at = time::precise_time_ns() + ms as u64 * 1000000;
+    {
+        let mut timers = TIMERS.0.lock().unwrap();
+        timers.push(Timer { at: at, pulse: pulse });
+    }
+    TIMERS.1.notify_one();
+
+    // Whichever comes first, the timer or the timeout, the time is up
+    let _ = signal.wait_timeout_ms(ms);
+}
+
+/// Pulse to send at `at`, in `time::precise_time_ns`
+struct Timer {
+    at: u64,
+    pulse: Pulse,
+}
+
+// Ordered the other way around, so that the BinaryHeap pops the earliest first
+impl Ord for Timer {
+    fn cmp(&self, other: &Timer) -> Ordering {
+        other.at.cmp(&self.at)
+    }
+}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Timer) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Timer) -> bool {
+        self.at == other.at
+    }
+}
+
+impl Eq for Timer {}
+
+lazy_static! {
+    static ref TIMERS: (Mutex<BinaryHeap<Timer>>, Condvar) = {
+        // The thread waits for the initialization to be done before using TIMERS
+        thread::Builder::new().name("bran-timer".to_string()).spawn(run_timers).unwrap();
+        (Mutex::new(BinaryHeap::new()), Condvar::new())
+    };
+}
+
+fn run_timers() {
+    let &(ref lock, ref cvar) = &*TIMERS;
+    let mut timers = lock.lock().unwrap();
+    loop {
+        let now = time::precise_time_ns();
+        while timers.peek().map_or(false, |t| t.at <= now) {
+            timers.pop().unwrap().pulse.pulse();
+        }
+        timers = match timers.peek().map(|t| t.at) {
+            Some(at) => {
+                let ms = ((at - now + 999999) / 1000000) as u32;
+                cvar.wait_timeout_ms(timers, ms).unwrap().0
+            }
+            None => cvar.wait(timers).unwrap(),
+        };
+    }
+}